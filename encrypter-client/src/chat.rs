@@ -1,20 +1,97 @@
-use crate::network::PRIVATE_KEY;
-use x25519_dalek::{PublicKey, SharedSecret};
+use crate::export::Contact;
+use crate::history::HistoryEntry;
+use crate::network;
+use encrypter_core::identity::{SignedPublicKey, IDENTITY_KEY_SIZE};
+use encrypter_core::ratchet::Session;
+use serde::{Deserialize, Serialize};
+use x25519_dalek::PublicKey;
 
+// Saved in the keystore, except for the message history and online status
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct Chat {
-    pub shared_key: SharedSecret,
-    pub messages: Vec<String>,
+    pub identity_key: [u8; IDENTITY_KEY_SIZE],
+    public_key: [u8; 32],
+    // Messages to offline peers are queued by the server until they reconnect
+    #[serde(skip)]
+    pub online: bool,
+    // Set when the user has compared safety numbers with the contact
+    pub verified: bool,
+    pub session: Session,
+    // A different identity key than the pinned one, nothing can be sent until the
+    // user has accepted it
+    pub pending_key: Option<SignedPublicKey>,
+    #[serde(skip)]
+    pub messages: Vec<HistoryEntry>,
 }
 
 impl Chat {
-    pub fn new(public_key: [u8; 32]) -> Self {
+    // The signed key is expected to be verified by the caller
+    pub fn new(signed_key: &SignedPublicKey) -> Self {
+        let session = Session::new(
+            &network::private_key(),
+            PublicKey::from(signed_key.public_key),
+        );
+        Chat::from_session(signed_key, session, true)
+    }
+
+    // For sessions started from a prekey bundle, where the peer might be offline
+    pub fn from_session(signed_key: &SignedPublicKey, session: Session, online: bool) -> Self {
+        Chat {
+            identity_key: signed_key.identity_key,
+            public_key: signed_key.public_key,
+            online,
+            verified: false,
+            session,
+            pending_key: None,
+            messages: Vec::new(),
+        }
+    }
+
+    // Contacts imported from another machine keep their verification, the session is
+    // replaced as soon as the peer shows up with its current key
+    pub fn from_contact(contact: &Contact) -> Self {
         Chat {
-            shared_key: PRIVATE_KEY.diffie_hellman(&PublicKey::from(public_key)),
+            identity_key: contact.identity_key,
+            public_key: contact.public_key,
+            online: false,
+            verified: true,
+            session: Session::new(&network::private_key(), PublicKey::from(contact.public_key)),
+            pending_key: None,
             messages: Vec::new(),
         }
     }
 
-    pub fn change_key(&mut self, public_key: [u8; 32]) {
-        self.shared_key = PRIVATE_KEY.diffie_hellman(&PublicKey::from(public_key));
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    // Replaces the session with one the peer started from our prekeys
+    pub fn restart(&mut self, signed_key: &SignedPublicKey, session: Session) {
+        if self.identity_key != signed_key.identity_key {
+            self.verified = false;
+            self.identity_key = signed_key.identity_key;
+        }
+        self.public_key = signed_key.public_key;
+        self.session = session;
+    }
+
+    // Starts a new ratchet session if the key changed, any messages in flight in the
+    // old session are lost. A peer reconnecting with the same key keeps the session so
+    // messages queued while it was offline can still be decrypted.
+    pub fn change_key(&mut self, signed_key: &SignedPublicKey) {
+        if self.identity_key != signed_key.identity_key {
+            // A new identity needs to be verified again
+            self.verified = false;
+            self.identity_key = signed_key.identity_key;
+        }
+        if self.public_key != signed_key.public_key {
+            self.public_key = signed_key.public_key;
+            self.reset_session();
+        }
+    }
+
+    // Needed on both sides when either peer changes its X25519 key
+    pub fn reset_session(&mut self) {
+        self.session = Session::new(&network::private_key(), PublicKey::from(self.public_key));
     }
 }
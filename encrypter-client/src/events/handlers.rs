@@ -1,7 +1,22 @@
-use crate::{network::ServerConnection, ActiveBlock, App, Route, RouteId};
+use crate::{
+    export::IdentityExport,
+    group::Group,
+    history::HistoryEntry,
+    keystore::Keystore,
+    network::{self, ServerConnection},
+    ActiveBlock, App, RouteId,
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 
-use encrypter_core::{EncryptedMessage, Message, Protocol};
+use encrypter_core::error::ErrorCode;
+use encrypter_core::group::{self, MAX_GROUP_MEMBERS};
+use encrypter_core::identity::KeyChange;
+use encrypter_core::{fingerprint, is_valid_id, ClientMessage};
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
 
+use crate::ui::command_line::Command;
 use crate::ui::StatefulWidget;
 use termion::event::Key;
 
@@ -14,6 +29,7 @@ typ som handle right och handle left
 */
 pub fn handle_block_events(input: Key, app: &mut App) {
     if input == Key::Esc {
+        app.command_line.cancel();
         app.set_current_route_state(Some(ActiveBlock::Empty), None);
         return;
     }
@@ -21,6 +37,9 @@ pub fn handle_block_events(input: Key, app: &mut App) {
         ActiveBlock::Id => {
             id_handler(input, app);
         }
+        ActiveBlock::Passphrase => {
+            passphrase_handler(input, app);
+        }
         /* ActiveBlock::ServerAddr => {
             server_handler(input, app);
         }*/
@@ -32,6 +51,9 @@ pub fn handle_block_events(input: Key, app: &mut App) {
         }
         ActiveBlock::CommandLine => {
             app.command_line.handle_event(input);
+            if let Some(command) = app.command_line.take_command() {
+                handle_command(command, app);
+            }
         }
         ActiveBlock::Empty => match input {
             Key::Char('\n') => {
@@ -55,6 +77,235 @@ pub fn handle_block_events(input: Key, app: &mut App) {
     }
 }
 
+pub fn handle_command(command: Command, app: &mut App) {
+    // These don't need a selected chat
+    let command = match command {
+        Command::Chat(id) => return start_chat(id, app),
+        Command::Export(path, passphrase) => return export_identity(&path, &passphrase, app),
+        Command::Import(path, passphrase) => return import_identity(&path, &passphrase, app),
+        Command::RotateKey => return rotate_key(app),
+        Command::Group(name, members) => return create_group(name, members, app),
+        command => command,
+    };
+    let index = if let Some(index) = app.current_chat_position() {
+        index
+    } else if app.current_group_position().is_some() {
+        app.command_line
+            .show_error("The command isn't available for groups");
+        return;
+    } else {
+        app.command_line
+            .show_error("You need to select a chat from the chat list first");
+        return;
+    };
+    let (peer_id, chat) = &mut app.chats[index];
+    match command {
+        Command::SafetyNumber => {
+            let safety_number = fingerprint::safety_number(
+                &app.id,
+                &network::identity_key().public.to_bytes(),
+                peer_id,
+                &chat.identity_key,
+            );
+            app.command_line
+                .show_info_message(format!("Safety number with {}: {}", peer_id, safety_number));
+        }
+        Command::Verify => {
+            chat.verified = true;
+            app.keystore_dirty = true;
+            app.command_line
+                .show_info_message(format!("Marked {} as verified", peer_id));
+        }
+        Command::AcceptKey => match chat.pending_key.take() {
+            Some(signed_key) => {
+                app.pins.replace(peer_id, signed_key.identity_key);
+                chat.change_key(&signed_key);
+                chat.messages.push(HistoryEntry::notice(
+                    "Accepted the new identity key, compare :safety again to verify it",
+                ));
+                app.keystore_dirty = true;
+                app.command_line
+                    .show_info_message(format!("Accepted the new key of {}", peer_id));
+            }
+            None => app
+                .command_line
+                .show_error(format!("The key of {} hasn't changed", peer_id)),
+        },
+        Command::Unverify => {
+            chat.verified = false;
+            app.keystore_dirty = true;
+            app.command_line
+                .show_info_message(format!("Marked {} as unverified", peer_id));
+        }
+        Command::Chat(_)
+        | Command::Export(..)
+        | Command::Import(..)
+        | Command::RotateKey
+        | Command::Group(..) => unreachable!("Handled before a chat is selected"),
+    }
+}
+
+// Selects the chat if there is one, otherwise the peer's prekey bundle is fetched
+// and the chat is created once it arrives
+fn start_chat(id: String, app: &mut App) {
+    if id == app.id {
+        app.command_line
+            .show_error("Can't start a chat with yourself");
+        return;
+    }
+    if let Some(index) = app.chats.iter().position(|(peer_id, _)| peer_id == &id) {
+        app.current_chat_index = Some(app.groups.len() + index);
+        return;
+    }
+    match &app.connection {
+        Some(connection) => {
+            if let Err(err) = connection.send(ClientMessage::FetchPrekeyBundle(id.clone())) {
+                error!("Failed to request prekeys: {}", err);
+            }
+            app.command_line
+                .show_info_message(format!("Fetching keys for {}", id));
+        }
+        None => app.command_line.show_error("Not connected"),
+    }
+}
+
+// Every member needs a chat with us so our sender key can be sent over the pairwise session
+fn create_group(name: String, mut members: Vec<String>, app: &mut App) {
+    if app.get_current_route().id != RouteId::Chat {
+        app.command_line
+            .show_error("Log in before creating a group");
+        return;
+    }
+    members.sort();
+    members.dedup();
+    members.retain(|member| member != &app.id);
+    if members.is_empty() || members.len() >= MAX_GROUP_MEMBERS {
+        app.command_line.show_error(format!(
+            "A group needs between 1 and {} other members",
+            MAX_GROUP_MEMBERS - 1
+        ));
+        return;
+    }
+    let unknown = members
+        .iter()
+        .filter(|member| app.chats.iter().all(|(peer_id, _)| peer_id != *member))
+        .cloned()
+        .collect::<Vec<String>>();
+    if !unknown.is_empty() {
+        app.command_line.show_error(format!(
+            "Start a chat with {} before adding them to a group",
+            unknown.join(", ")
+        ));
+        return;
+    }
+    members.insert(0, app.id.clone());
+    let group = Group::new(group::new_group_id(), name, members);
+    let index = app.add_group(group);
+    app.current_chat_index = Some(index);
+}
+
+// Announces a new X25519 key signed with the identity key. The key is only replaced once
+// the server has accepted the announcement, peers that are offline get it when they reconnect.
+fn rotate_key(app: &mut App) {
+    if app.connection.is_none() || app.get_current_route().id != RouteId::Chat {
+        app.command_line
+            .show_error("Log in before rotating the key");
+        return;
+    }
+    let new_key = StaticSecret::new(&mut OsRng);
+    let key_change = KeyChange::sign(
+        &app.id,
+        &PublicKey::from(&network::private_key()),
+        &PublicKey::from(&new_key),
+        network::identity_key(),
+    );
+    let reference = app.new_reference();
+    let message = ClientMessage::RotateKey(reference, key_change.clone());
+    let result = match &app.connection {
+        Some(connection) => connection.send(message),
+        None => Err("Not connected".into()),
+    };
+    if let Err(err) = result {
+        error!("Failed to send key change: {}", err);
+        app.command_line.show_error("Failed to rotate key");
+        return;
+    }
+    // Replaces an earlier rotation the server never answered
+    app.pending_rotation = Some((reference, new_key, key_change));
+    app.command_line
+        .show_info_message("Sent the new key, waiting for the server to accept it");
+}
+
+// The file is never overwritten so an existing export can't be lost by accident
+fn export_identity(path: &str, passphrase: &str, app: &mut App) {
+    if app.keystore.is_none() {
+        app.command_line
+            .show_error("Unlock the keystore before exporting the identity");
+        return;
+    }
+    let export = IdentityExport::new(
+        app.id.clone(),
+        network::identity_key().to_bytes().to_vec(),
+        &app.chats,
+    );
+    let result = export.armor(passphrase).and_then(|armored| {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(armored.as_bytes())?;
+        Ok(())
+    });
+    match result {
+        Ok(()) => app.command_line.show_info_message(format!(
+            "Exported identity and {} verified contact(s) to {}",
+            export.contacts.len(),
+            path
+        )),
+        Err(err) => {
+            error!("Failed to export identity: {}", err);
+            app.command_line
+                .show_error(format!("Failed to export identity: {}", err));
+        }
+    }
+}
+
+// The imported identity is stored in a new keystore once the user enters a passphrase for it
+fn import_identity(path: &str, passphrase: &str, app: &mut App) {
+    if app.keystore.is_some() || Keystore::default_path().exists() {
+        app.command_line.show_error(
+            "A keystore already exists, move it away before importing another identity",
+        );
+        return;
+    }
+    let export = fs::read_to_string(path)
+        .map_err(|err| err.into())
+        .and_then(|armored| IdentityExport::dearmor(&armored, passphrase))
+        .and_then(|export| {
+            ed25519_dalek::Keypair::from_bytes(&export.identity_key)
+                .map_err(|_| "The export contains an invalid identity key")?;
+            Ok(export)
+        });
+    match export {
+        Ok(export) => {
+            app.command_line.show_info_message(format!(
+                "Imported identity {}, enter a passphrase for the new keystore",
+                export.id
+            ));
+            app.id = export.id.clone();
+            app.pending_import = Some(export);
+            app.set_current_route_state(
+                Some(ActiveBlock::Passphrase),
+                Some(ActiveBlock::Passphrase),
+            );
+            app.cursor_vertical_offset = 7;
+            app.input_cursor_pos = app.passphrase.len() as u16;
+        }
+        Err(err) => {
+            error!("Failed to import identity: {}", err);
+            app.command_line
+                .show_error(format!("Failed to import identity: {}", err));
+        }
+    }
+}
+
 pub fn handle_right_event(app: &mut App) {
     let current_route = app.get_current_route();
     if let ActiveBlock::ChatList = current_route.hovered_block {
@@ -84,15 +335,13 @@ pub fn chat_list_handler(input: Key, app: &mut App) {
         }
         Key::Down => {
             if let Some(index) = app.current_chat_index {
-                if index < app.chats.len() - 1 {
+                if index + 1 < app.groups.len() + app.chats.len() {
                     app.current_chat_index = Some(index + 1);
                 }
             }
         }
-        Key::Char('\n') => {
-            if !app.chats.is_empty() {
-                app.current_chat_index = Some(0);
-            }
+        Key::Char('\n') if !app.groups.is_empty() || !app.chats.is_empty() => {
+            app.current_chat_index = Some(0);
         }
         _ => {}
     }
@@ -104,29 +353,21 @@ pub fn chat_window_handler(input: Key, app: &mut App) {
     } else if app.current_chat_index.is_some() {
         match input {
             Key::Char('\n') => {
+                if let Some(chat) = app.get_current_chat() {
+                    if chat.pending_key.is_some() {
+                        app.command_line
+                            .show_error("The key of this contact changed, run :accept first");
+                        return;
+                    }
+                }
                 let message = app.message_draft.drain(..).collect::<String>();
-                let message = Message {
-                    from: app.id.clone(),
-                    to: app.chats[app.current_chat_index.unwrap()].0.clone(), // Safe because of previous if let
-                    content: message.as_bytes().to_vec(),
-                };
-                if let Some(current_chat) = app.get_current_chat() {
-                    current_chat
-                        .messages
-                        .push(format!("Me: {}", String::from_utf8_lossy(&message.content)));
-                    let encrypted_message =
-                        EncryptedMessage::create(message, &current_chat.shared_key);
-                    app.connection
-                        .as_ref()
-                        .unwrap()
-                        .send(Protocol::Message(encrypted_message))
-                        .expect("Failed to send message");
+                if let Err(err) = app.send_text(message) {
+                    error!("Failed to send message: {}", err);
+                    app.command_line.show_error("Failed to send message");
                 }
             }
-            Key::Char(c) => {
-                if app.message_draft.len() < encrypter_core::MESSAGE_MAX_SIZE {
-                    app.message_draft.push(c);
-                }
+            Key::Char(c) if app.message_draft.len() < encrypter_core::MESSAGE_MAX_SIZE => {
+                app.message_draft.push(c);
             }
             Key::Backspace => {
                 app.message_draft.pop();
@@ -137,27 +378,33 @@ pub fn chat_window_handler(input: Key, app: &mut App) {
 }
 
 pub fn id_handler(input: Key, app: &mut App) {
+    if app.connection.is_some() {
+        // Waiting for the server to accept or reject the registration
+        return;
+    }
     match input {
         Key::Char('\n') => {
-            match ServerConnection::new(&app.server_addr, app.id.clone()) {
-                Ok(connection) => {
-                    app.connection = Some(connection);
-                }
-                Err(err) => {
-                    error!("Couldn't connect to server {:#?}", err);
-                }
+            if !is_valid_id(&app.id) {
+                app.command_line
+                    .show_error(ErrorCode::InvalidId.to_string());
+                return;
             }
-            app.push_route(Route {
-                id: RouteId::Chat,
-                hovered_block: ActiveBlock::ChatList,
-                active_block: ActiveBlock::ChatList,
-            });
-        }
-        Key::Char(c) => {
-            if app.id.len() < encrypter_core::ID_MAX_SIZE {
-                app.id.push(c);
+            if app.keystore.is_some() {
+                connect(app);
+            } else {
+                // The keystore needs to be unlocked before connecting
+                app.set_current_route_state(
+                    Some(ActiveBlock::Passphrase),
+                    Some(ActiveBlock::Passphrase),
+                );
+                app.cursor_vertical_offset = 7;
+                app.input_cursor_pos = app.passphrase.len() as u16;
+                return;
             }
         }
+        Key::Char(c) if app.id.len() < encrypter_core::ID_MAX_SIZE => {
+            app.id.push(c);
+        }
         Key::Backspace => {
             app.id.pop();
         }
@@ -165,3 +412,51 @@ pub fn id_handler(input: Key, app: &mut App) {
     }
     app.input_cursor_pos = app.id.len() as u16;
 }
+
+pub fn passphrase_handler(input: Key, app: &mut App) {
+    match input {
+        Key::Char('\n') => match app.unlock_keystore() {
+            Ok(()) => connect(app),
+            Err(err) => {
+                error!("Couldn't unlock keystore: {}", err);
+                app.command_line.show_error(err.to_string());
+            }
+        },
+        Key::Up => {
+            app.set_current_route_state(Some(ActiveBlock::Id), Some(ActiveBlock::Id));
+            app.cursor_vertical_offset = 4;
+            app.input_cursor_pos = app.id.len() as u16;
+            return;
+        }
+        Key::Char(c) => {
+            app.passphrase.push(c);
+        }
+        Key::Backspace => {
+            app.passphrase.pop();
+        }
+        _ => {}
+    }
+    app.input_cursor_pos = app.passphrase.len() as u16;
+}
+
+fn connect(app: &mut App) {
+    match ServerConnection::new(&app.server_addr, app.id.clone()) {
+        Ok(connection) => {
+            let server_info = connection.server_info();
+            app.command_line.show_info_message(format!(
+                "Connected to {}. {}",
+                server_info.server_name, server_info.motd
+            ));
+            // The chat screen is shown once the server has accepted the registration
+            app.connection = Some(connection);
+        }
+        Err(err) => {
+            error!("Couldn't connect to server {:#?}", err);
+            app.command_line.show_error(err.to_string());
+        }
+    }
+    // Entering the id again is needed if the registration fails
+    app.set_current_route_state(Some(ActiveBlock::Id), Some(ActiveBlock::Id));
+    app.cursor_vertical_offset = 4;
+    app.input_cursor_pos = app.id.len() as u16;
+}
@@ -0,0 +1,142 @@
+use crate::chat::Chat;
+use crate::keystore;
+use encrypter_core::identity::IDENTITY_KEY_SIZE;
+use encrypter_core::Result;
+use serde::{Deserialize, Serialize};
+
+const ARMOR_BEGIN: &str = "-----BEGIN ENCRYPTER IDENTITY-----";
+const ARMOR_END: &str = "-----END ENCRYPTER IDENTITY-----";
+const ARMOR_LINE_LENGTH: usize = 64;
+
+/// A contact that has been verified by comparing safety numbers
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Contact {
+    pub id: String,
+    pub identity_key: [u8; IDENTITY_KEY_SIZE],
+    pub public_key: [u8; 32],
+}
+
+/// Identity moved between machines. Only the identity keypair and the verified contacts
+/// are included, the importing client creates a new X25519 key and new sessions
+/// since ratchet sessions can't be used from two machines at once.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IdentityExport {
+    pub id: String,
+    /// Ed25519 keypair bytes, secret key followed by the public key
+    pub identity_key: Vec<u8>,
+    pub contacts: Vec<Contact>,
+}
+
+impl IdentityExport {
+    pub fn new(id: String, identity_key: Vec<u8>, chats: &[(String, Chat)]) -> Self {
+        let contacts = chats
+            .iter()
+            .filter(|(_, chat)| chat.verified)
+            .map(|(id, chat)| Contact {
+                id: id.clone(),
+                identity_key: chat.identity_key,
+                public_key: chat.public_key(),
+            })
+            .collect();
+        IdentityExport {
+            id,
+            identity_key,
+            contacts,
+        }
+    }
+
+    /// Encrypts the export with the passphrase the same way as the keystore
+    /// and encodes it as base64 between armor lines. The passphrase can't be empty,
+    /// anyone could read the identity otherwise.
+    pub fn armor(&self, passphrase: &str) -> Result<String> {
+        if passphrase.is_empty() {
+            return Err("The passphrase can't be empty".into());
+        }
+        let sealed = keystore::seal_with_passphrase(&bincode::serialize(self)?, passphrase)?;
+        let encoded = base64::encode(&sealed);
+        let mut armored = String::with_capacity(encoded.len() + 128);
+        armored.push_str(ARMOR_BEGIN);
+        armored.push('\n');
+        for line in encoded.as_bytes().chunks(ARMOR_LINE_LENGTH) {
+            // base64 output is always ascii
+            armored.push_str(std::str::from_utf8(line)?);
+            armored.push('\n');
+        }
+        armored.push_str(ARMOR_END);
+        armored.push('\n');
+        Ok(armored)
+    }
+
+    pub fn dearmor(armored: &str, passphrase: &str) -> Result<Self> {
+        let mut lines = armored
+            .lines()
+            .map(str::trim)
+            .skip_while(|line| *line != ARMOR_BEGIN);
+        if lines.next().is_none() {
+            return Err("Not an exported identity".into());
+        }
+        let mut encoded = String::new();
+        for line in &mut lines {
+            if line == ARMOR_END {
+                let sealed = base64::decode(&encoded)
+                    .map_err(|err| format!("Invalid exported identity: {}", err))?;
+                let plaintext = keystore::open_with_passphrase(&sealed, passphrase)?;
+                return Ok(bincode::deserialize(&plaintext)?);
+            }
+            encoded.push_str(line);
+        }
+        Err("The exported identity is truncated".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export() -> IdentityExport {
+        IdentityExport {
+            id: "alice".to_string(),
+            identity_key: vec![7; 64],
+            contacts: vec![Contact {
+                id: "bob".to_string(),
+                identity_key: [1; IDENTITY_KEY_SIZE],
+                public_key: [2; 32],
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trips_through_armor() {
+        let armored = export().armor("passphrase").unwrap();
+        assert!(armored.starts_with(ARMOR_BEGIN));
+        assert!(armored.lines().all(|line| line.len() <= ARMOR_LINE_LENGTH));
+        // Text around the armor, like from an email, is ignored
+        let surrounded = format!("Here it is:\n\n{}\nRegards\n", armored);
+        let imported = IdentityExport::dearmor(&surrounded, "passphrase").unwrap();
+        assert_eq!(imported.id, "alice");
+        assert_eq!(imported.identity_key, vec![7; 64]);
+        assert_eq!(imported.contacts.len(), 1);
+        assert_eq!(imported.contacts[0].id, "bob");
+        assert_eq!(imported.contacts[0].public_key, [2; 32]);
+    }
+
+    #[test]
+    fn rejects_empty_passphrase() {
+        assert!(export().armor("").is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase() {
+        let armored = export().armor("passphrase").unwrap();
+        assert!(IdentityExport::dearmor(&armored, "wrong").is_err());
+    }
+
+    #[test]
+    fn rejects_missing_armor_lines() {
+        let armored = export().armor("passphrase").unwrap();
+        let truncated = armored.replace(ARMOR_END, "");
+        assert!(IdentityExport::dearmor(&truncated, "passphrase").is_err());
+        let headless = armored.replace(ARMOR_BEGIN, "");
+        assert!(IdentityExport::dearmor(&headless, "passphrase").is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use crate::history::HistoryEntry;
+use encrypter_core::group::{
+    GroupId, GroupMessage, GroupUpdate, ReceivedSenderKey, SenderKey, SenderKeyDistribution,
+};
+use encrypter_core::padding::PaddingPolicy;
+use encrypter_core::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Saved in the keystore, except for the message history
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Group {
+    pub id: GroupId,
+    pub name: String,
+    // Everyone in the group including us
+    pub members: Vec<String>,
+    sender_key: SenderKey,
+    // Filled in as the other members send us their keys over the pairwise sessions
+    member_keys: HashMap<String, ReceivedSenderKey>,
+    #[serde(skip)]
+    pub messages: Vec<HistoryEntry>,
+}
+
+impl Group {
+    pub fn new(id: GroupId, name: String, members: Vec<String>) -> Self {
+        Group {
+            id,
+            name,
+            members,
+            sender_key: SenderKey::new(),
+            member_keys: HashMap::new(),
+            messages: Vec::new(),
+        }
+    }
+
+    // Sent to the other members so they can decrypt our messages
+    pub fn update(&self) -> Result<GroupUpdate> {
+        Ok(GroupUpdate {
+            group_id: self.id,
+            name: self.name.clone(),
+            members: self.members.clone(),
+            sender_key: self.sender_key.distribution()?,
+        })
+    }
+
+    pub fn set_member_key(&mut self, member: &str, sender_key: SenderKeyDistribution) {
+        self.member_keys
+            .insert(member.to_string(), ReceivedSenderKey::from(sender_key));
+    }
+
+    pub fn recipients(&self, own_id: &str) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|member| *member != own_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn encrypt(
+        &mut self,
+        from: &str,
+        content: Vec<u8>,
+        padding: &PaddingPolicy,
+    ) -> Result<GroupMessage> {
+        self.sender_key.encrypt(self.id, from, content, padding)
+    }
+
+    pub fn decrypt(&mut self, message: &GroupMessage) -> Result<Vec<u8>> {
+        let from = message.from();
+        if !self.members.iter().any(|member| member == from) {
+            return Err(format!("{} isn't a member of {}", from, self.name).into());
+        }
+        match self.member_keys.get_mut(from) {
+            Some(sender_key) => sender_key.decrypt(message),
+            None => Err(format!("No key for {} in {} yet", from, self.name).into()),
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use chrono::{Local, TimeZone};
+use encrypter_core::{MessageBody, MessageId};
+use std::fmt;
+
+/// A text message in the history of a chat or group
+#[derive(Clone)]
+pub(crate) struct HistoryMessage {
+    pub id: MessageId,
+    // `None` for our own messages
+    pub from: Option<String>,
+    // From the sender's clock, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub text: String,
+    // Arrived after messages that were sent later
+    pub late: bool,
+    // The server couldn't deliver our message
+    pub undelivered: bool,
+}
+
+#[derive(Clone)]
+pub(crate) enum HistoryEntry {
+    Message(HistoryMessage),
+    // Things that happen in a chat, like key changes and missing messages
+    Notice(String),
+}
+
+impl HistoryEntry {
+    pub fn sent(body: &MessageBody, text: String) -> Self {
+        HistoryEntry::Message(HistoryMessage {
+            id: body.id,
+            from: None,
+            timestamp: body.timestamp,
+            text,
+            late: false,
+            undelivered: false,
+        })
+    }
+
+    pub fn received(from: &str, body: &MessageBody, text: String, late: bool) -> Self {
+        HistoryEntry::Message(HistoryMessage {
+            id: body.id,
+            from: Some(from.to_string()),
+            timestamp: body.timestamp,
+            text,
+            late,
+            undelivered: false,
+        })
+    }
+
+    pub fn notice(notice: impl Into<String>) -> Self {
+        HistoryEntry::Notice(notice.into())
+    }
+}
+
+// Finds one of our own messages by its id, to mark it when the server reports an error
+pub(crate) fn find_message_mut(
+    history: &mut [HistoryEntry],
+    id: MessageId,
+) -> Option<&mut HistoryMessage> {
+    history.iter_mut().rev().find_map(|entry| match entry {
+        HistoryEntry::Message(message) if message.id == id => Some(message),
+        _ => None,
+    })
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryEntry::Message(message) => {
+                let time = Local
+                    .timestamp_millis_opt(message.timestamp as i64)
+                    .single();
+                if let Some(time) = time {
+                    write!(f, "[{}] ", time.format("%H:%M"))?;
+                }
+                match &message.from {
+                    Some(from) => write!(f, "{}: {}", from, message.text)?,
+                    None => write!(f, "Me: {}", message.text)?,
+                }
+                if message.late {
+                    write!(f, " (arrived late)")?;
+                }
+                if message.undelivered {
+                    write!(f, " (not delivered)")?;
+                }
+                Ok(())
+            }
+            HistoryEntry::Notice(notice) => write!(f, "-- {} --", notice),
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use crate::chat::Chat;
+use crate::group::Group;
+use crate::pins::KeyPins;
+use crate::prekeys::PrekeyStore;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use encrypter_core::ratchet::Session;
+use encrypter_core::{Result, NONCE_SIZE};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KEYSTORE_VERSION: u8 = 2;
+// Used the Argon2 defaults, still read so older keystores and exports can be opened.
+// Keystores are saved with the current version the next time they change.
+const LEGACY_KEYSTORE_VERSION: u8 = 1;
+// Argon2id settings for the current version, the keystore holds the long-term identity
+// so the key derivation is made expensive to slow down guessing the passphrase
+const ARGON2_MEMORY_KIB: u32 = 64 * 1024;
+const ARGON2_ITERATIONS: u32 = 3;
+const ARGON2_PARALLELISM: u32 = 1;
+const SALT_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const HEADER_SIZE: usize = 1 + SALT_SIZE + NONCE_SIZE;
+const DEFAULT_KEYSTORE_PATH: &str = "encrypter.keystore";
+
+/// Everything the client keeps between runs
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct KeystoreData {
+    /// Ed25519 keypair bytes, secret key followed by the public key
+    pub identity_key: Vec<u8>,
+    pub private_key: [u8; 32],
+    pub prekeys: PrekeyStore,
+    pub pins: KeyPins,
+    pub chats: Vec<(String, Chat)>,
+    pub groups: Vec<Group>,
+    pub previous_keys: Vec<PreviousKey>,
+}
+
+/// An X25519 key replaced by a key rotation. It's kept for a while together with the
+/// sessions that used it, so messages from peers that hadn't seen the rotation yet
+/// can still be read.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct PreviousKey {
+    pub private_key: [u8; 32],
+    /// Unix time in seconds
+    pub replaced_at: u64,
+    /// Peer id and session
+    pub sessions: Vec<(String, Session)>,
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// An unlocked keystore file. The file starts with a version byte, the Argon2 salt
+/// and the nonce followed by the ChaCha20-Poly1305 encrypted `KeystoreData`.
+/// The derived key is kept in memory so the keystore can be saved without
+/// asking for the passphrase again.
+pub(crate) struct Keystore {
+    path: PathBuf,
+    salt: [u8; SALT_SIZE],
+    key: [u8; KEY_SIZE],
+}
+
+impl Keystore {
+    /// Set `ENCRYPTER_KEYSTORE` to use another file than the default
+    pub fn default_path() -> PathBuf {
+        env::var("ENCRYPTER_KEYSTORE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(DEFAULT_KEYSTORE_PATH))
+    }
+
+    pub fn create(path: &Path, passphrase: &str, data: &KeystoreData) -> Result<Self> {
+        let mut salt = [0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        let keystore = Keystore {
+            path: path.to_path_buf(),
+            salt,
+            key: derive_key(passphrase, &salt, KEYSTORE_VERSION)?,
+        };
+        keystore.save(data)?;
+        Ok(keystore)
+    }
+
+    pub fn open(path: &Path, passphrase: &str) -> Result<(Self, KeystoreData)> {
+        let (salt, key, plaintext) = open_sealed(&fs::read(path)?, passphrase)?;
+        let data = bincode::deserialize(&plaintext)?;
+        let keystore = Keystore {
+            path: path.to_path_buf(),
+            salt,
+            key,
+        };
+        Ok((keystore, data))
+    }
+
+    // Written to a temporary file first so a crash can't leave a half written keystore
+    pub fn save(&self, data: &KeystoreData) -> Result<()> {
+        let contents = seal(&bincode::serialize(data)?, &self.salt, &self.key)?;
+        let temporary_path = self.path.with_extension("tmp");
+        write_private_file(&temporary_path, &contents)?;
+        fs::rename(&temporary_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Creates the file readable and writable by the owner only, a leftover file at the
+/// path is replaced since it could have been created with other permissions
+pub(crate) fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Encrypts `plaintext` with a key derived from the passphrase and a new salt,
+/// in the same format as the keystore file
+pub(crate) fn seal_with_passphrase(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let mut salt = [0; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    seal(
+        plaintext,
+        &salt,
+        &derive_key(passphrase, &salt, KEYSTORE_VERSION)?,
+    )
+}
+
+pub(crate) fn open_with_passphrase(sealed: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    open_sealed(sealed, passphrase).map(|(_, _, plaintext)| plaintext)
+}
+
+fn seal(plaintext: &[u8], salt: &[u8; SALT_SIZE], key: &[u8; KEY_SIZE]) -> Result<Vec<u8>> {
+    let mut nonce = [0; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let mut sealed = Vec::with_capacity(HEADER_SIZE + plaintext.len() + 16);
+    sealed.push(KEYSTORE_VERSION);
+    sealed.extend_from_slice(salt);
+    let ciphertext = ChaCha20Poly1305::new(&Key::from(*key))
+        .encrypt(
+            &Nonce::from(nonce),
+            Payload {
+                msg: plaintext,
+                aad: &sealed,
+            },
+        )
+        .map_err(|_| "Encryption failed")?;
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+// Returns the salt and the derived key together with the plaintext so the
+// keystore can be saved again without rerunning the key derivation. For legacy
+// versions the key for the current version is derived as well.
+fn open_sealed(
+    sealed: &[u8],
+    passphrase: &str,
+) -> Result<([u8; SALT_SIZE], [u8; KEY_SIZE], Vec<u8>)> {
+    if sealed.len() < HEADER_SIZE {
+        return Err("The encrypted data is truncated".into());
+    }
+    let version = sealed[0];
+    if version != KEYSTORE_VERSION && version != LEGACY_KEYSTORE_VERSION {
+        return Err(format!("Unsupported keystore version {}", version).into());
+    }
+    let mut salt = [0; SALT_SIZE];
+    let mut nonce = [0; NONCE_SIZE];
+    salt.copy_from_slice(&sealed[1..1 + SALT_SIZE]);
+    nonce.copy_from_slice(&sealed[1 + SALT_SIZE..HEADER_SIZE]);
+    let key = derive_key(passphrase, &salt, version)?;
+    let plaintext = ChaCha20Poly1305::new(&Key::from(key))
+        .decrypt(
+            &Nonce::from(nonce),
+            Payload {
+                msg: &sealed[HEADER_SIZE..],
+                aad: &sealed[..1 + SALT_SIZE],
+            },
+        )
+        .map_err(|_| "Wrong passphrase or corrupted data")?;
+    let key = if version == KEYSTORE_VERSION {
+        key
+    } else {
+        derive_key(passphrase, &salt, KEYSTORE_VERSION)?
+    };
+    Ok((salt, key, plaintext))
+}
+
+fn derive_key(passphrase: &str, salt: &[u8; SALT_SIZE], version: u8) -> Result<[u8; KEY_SIZE]> {
+    let params = if version == LEGACY_KEYSTORE_VERSION {
+        Params::default()
+    } else {
+        Params::new(
+            ARGON2_MEMORY_KIB,
+            ARGON2_ITERATIONS,
+            ARGON2_PARALLELISM,
+            Some(KEY_SIZE),
+        )
+        .map_err(|err| format!("Invalid key derivation parameters: {}", err))?
+    };
+    let mut key = [0; KEY_SIZE];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| format!("Key derivation failed: {}", err))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Removes the directory when the test ends, even if it fails
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!(
+                "encrypter-keystore-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn keystore(&self) -> PathBuf {
+            self.0.join("test.keystore")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn data(private_key: [u8; 32]) -> KeystoreData {
+        KeystoreData {
+            identity_key: vec![7; 64],
+            private_key,
+            prekeys: PrekeyStore::new(),
+            pins: KeyPins::new(),
+            chats: Vec::new(),
+            groups: Vec::new(),
+            previous_keys: vec![PreviousKey {
+                private_key: [3; 32],
+                replaced_at: 42,
+                sessions: Vec::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trips_through_file() {
+        let dir = TempDir::new("round-trip");
+        let keystore = Keystore::create(&dir.keystore(), "passphrase", &data([1; 32])).unwrap();
+
+        let (_, opened) = Keystore::open(&dir.keystore(), "passphrase").unwrap();
+        assert_eq!(opened.identity_key, vec![7; 64]);
+        assert_eq!(opened.private_key, [1; 32]);
+        assert_eq!(opened.previous_keys[0].replaced_at, 42);
+
+        // Saving reuses the derived key, the passphrase still opens it
+        keystore.save(&data([2; 32])).unwrap();
+        let (reopened_keystore, reopened) = Keystore::open(&dir.keystore(), "passphrase").unwrap();
+        assert_eq!(reopened.private_key, [2; 32]);
+        reopened_keystore.save(&data([3; 32])).unwrap();
+        let (_, reopened) = Keystore::open(&dir.keystore(), "passphrase").unwrap();
+        assert_eq!(reopened.private_key, [3; 32]);
+        assert!(!dir.keystore().with_extension("tmp").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn is_only_readable_by_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("permissions");
+        // A leftover temporary file with other permissions isn't reused
+        fs::write(dir.keystore().with_extension("tmp"), b"leftover").unwrap();
+        Keystore::create(&dir.keystore(), "passphrase", &data([1; 32])).unwrap();
+        let mode = fs::metadata(dir.keystore()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase() {
+        let dir = TempDir::new("wrong-passphrase");
+        Keystore::create(&dir.keystore(), "passphrase", &data([1; 32])).unwrap();
+        assert!(Keystore::open(&dir.keystore(), "wrong").is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_or_tampered_file() {
+        let sealed = seal_with_passphrase(b"secret", "passphrase").unwrap();
+        assert_eq!(
+            open_with_passphrase(&sealed, "passphrase").unwrap(),
+            b"secret"
+        );
+        assert!(open_with_passphrase(&sealed[..HEADER_SIZE - 1], "passphrase").is_err());
+        assert!(open_with_passphrase(&sealed[..sealed.len() - 1], "passphrase").is_err());
+        for position in &[0, 1, HEADER_SIZE - 1, HEADER_SIZE, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[*position] ^= 1;
+            assert!(open_with_passphrase(&tampered, "passphrase").is_err());
+        }
+    }
+
+    #[test]
+    fn opens_legacy_version() {
+        let mut salt = [0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        let legacy_key = derive_key("passphrase", &salt, LEGACY_KEYSTORE_VERSION).unwrap();
+        let mut sealed = seal(b"secret", &salt, &legacy_key).unwrap();
+        sealed[0] = LEGACY_KEYSTORE_VERSION;
+        // The version is covered by the tag, so it has to be sealed as a legacy file
+        let mut nonce = [0; NONCE_SIZE];
+        nonce.copy_from_slice(&sealed[1 + SALT_SIZE..HEADER_SIZE]);
+        let ciphertext = ChaCha20Poly1305::new(&Key::from(legacy_key))
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: b"secret",
+                    aad: &sealed[..1 + SALT_SIZE],
+                },
+            )
+            .unwrap();
+        sealed.truncate(HEADER_SIZE);
+        sealed.extend_from_slice(&ciphertext);
+
+        let (_, key, plaintext) = open_sealed(&sealed, "passphrase").unwrap();
+        assert_eq!(plaintext, b"secret");
+        // Saved again with the current version
+        assert_eq!(
+            key,
+            derive_key("passphrase", &salt, KEYSTORE_VERSION).unwrap()
+        );
+    }
+}
@@ -4,9 +4,30 @@ extern crate log;
 use simplelog::*;
 
 use crate::events::{Event, Events};
+use crate::network::NetworkEvent;
 use chat::Chat;
-use encrypter_core::Protocol;
+use encrypter_core::error::{MessageReference, ServerError};
+use encrypter_core::fingerprint;
+use encrypter_core::group::{GroupId, GroupMessage, GroupUpdate, MAX_GROUP_MEMBERS};
+use encrypter_core::identity::{self, KeyChange, SignedPublicKey};
+use encrypter_core::padding::PaddingPolicy;
+use encrypter_core::ratchet::Session;
+use encrypter_core::sealed::SealedEnvelope;
+use encrypter_core::sequence::SequenceStatus;
+use encrypter_core::x3dh::{self, PrekeyBundle};
 use encrypter_core::Result;
+use encrypter_core::{
+    ClientMessage, EncryptedMessage, Message, MessageBody, MessageId, MessageKind, ServerMessage,
+};
+use export::IdentityExport;
+use group::Group;
+use history::HistoryEntry;
+use keystore::{Keystore, KeystoreData, PreviousKey};
+use pins::KeyPins;
+use prekeys::PrekeyStore;
+use rand::rngs::OsRng;
+use std::collections::VecDeque;
+use std::env;
 use std::fs::File;
 use std::io::Write;
 use termion::cursor::Goto;
@@ -16,14 +37,26 @@ use termion::raw::IntoRawMode;
 use termion::screen::AlternateScreen;
 use tui::backend::TermionBackend;
 use tui::Terminal;
+use x25519_dalek::{PublicKey, StaticSecret};
 
 mod chat;
 mod events;
+mod export;
+mod group;
+mod history;
+mod keystore;
 mod network;
+mod pins;
+mod prekeys;
 mod ui;
 
 use ui::command_line::CommandLine;
 
+// How many sent messages are remembered so errors from the server can be tied back to them
+const SENT_MESSAGES_MAX: usize = 100;
+// How many messages from new senders are held while their keys are fetched
+const UNCONFIRMED_MESSAGES_MAX: usize = 100;
+
 const DEFAULT_ROUTE: Route = Route {
     id: RouteId::StartScreen,
     active_block: ActiveBlock::Id,
@@ -32,15 +65,42 @@ const DEFAULT_ROUTE: Route = Route {
 
 pub struct App {
     id: String,
+    passphrase: String,
     server_addr: String,
+    // Index in the chat list, which shows the groups followed by the chats
     current_chat_index: Option<usize>,
     chats: Vec<(String, Chat)>,
+    groups: Vec<Group>,
     navigation_stack: Vec<Route>,
     input_cursor_pos: u16,
     cursor_vertical_offset: u16,
     message_draft: String,
     command_line: CommandLine,
     connection: Option<network::ServerConnection>,
+    keystore: Option<Keystore>,
+    // Identity from another machine, used instead of a new one when the keystore is created
+    pending_import: Option<IdentityExport>,
+    // Set when something that is saved in the keystore has changed
+    keystore_dirty: bool,
+    prekeys: PrekeyStore,
+    pins: KeyPins,
+    next_message_reference: MessageReference,
+    // Reference, recipient and id in the chat history of recently sent messages
+    sent_messages: VecDeque<(MessageReference, String, Option<MessageId>)>,
+    // Bucket sizes every outgoing message is padded to
+    padding: PaddingPolicy,
+    // Reference, new key and announcement of a key rotation the server hasn't
+    // acknowledged yet
+    pending_rotation: Option<(MessageReference, StaticSecret, KeyChange)>,
+    previous_keys: Vec<PreviousKey>,
+    // Parts of a peer list that was split over several messages
+    peer_list: Vec<(String, SignedPublicKey)>,
+    // Members and groups whose key is sent once the member's prekey bundle has arrived
+    pending_group_keys: Vec<(String, GroupId)>,
+    // Messages starting a session from senders without a pinned key, with the index of
+    // the previous key they were sealed to. Held until the server has sent the key
+    // bound to the sender's account.
+    unconfirmed_messages: VecDeque<(EncryptedMessage, Option<usize>)>,
 }
 
 impl App {
@@ -49,13 +109,894 @@ impl App {
             navigation_stack: vec![DEFAULT_ROUTE],
             cursor_vertical_offset: 4,
             id: String::new(),
+            passphrase: String::new(),
             connection: None,
+            keystore: None,
+            pending_import: None,
+            keystore_dirty: false,
             current_chat_index: None,
             message_draft: String::new(),
             command_line: CommandLine::new(),
             chats: Vec::new(),
+            groups: Vec::new(),
             input_cursor_pos: 0,
             server_addr: String::from("127.0.0.1:1337"),
+            prekeys: PrekeyStore::new(),
+            pins: KeyPins::new(),
+            next_message_reference: 0,
+            sent_messages: VecDeque::new(),
+            padding: padding_policy(),
+            pending_rotation: None,
+            previous_keys: Vec::new(),
+            peer_list: Vec::new(),
+            pending_group_keys: Vec::new(),
+            unconfirmed_messages: VecDeque::new(),
+        }
+    }
+
+    // Remembers a sent message and returns the reference the server will use
+    // if it reports an error for it
+    pub(crate) fn track_sent_message(
+        &mut self,
+        to: String,
+        history_id: Option<MessageId>,
+    ) -> MessageReference {
+        let reference = self.new_reference();
+        self.sent_messages.push_back((reference, to, history_id));
+        if self.sent_messages.len() > SENT_MESSAGES_MAX {
+            self.sent_messages.pop_front();
+        }
+        reference
+    }
+
+    pub(crate) fn new_reference(&mut self) -> MessageReference {
+        let reference = self.next_message_reference;
+        self.next_message_reference = self.next_message_reference.wrapping_add(1);
+        reference
+    }
+
+    // Loads the keys from the keystore or creates a new identity on the first run
+    pub(crate) fn unlock_keystore(&mut self) -> Result<()> {
+        if self.passphrase.is_empty() {
+            return Err("The passphrase can't be empty".into());
+        }
+        let path = Keystore::default_path();
+        let mut imported_contacts = Vec::new();
+        let (keystore, data) = if path.exists() {
+            if self.pending_import.is_some() {
+                return Err("A keystore already exists, move it away before importing".into());
+            }
+            Keystore::open(&path, &self.passphrase)?
+        } else {
+            let identity_key = match self.pending_import.take() {
+                Some(import) => {
+                    info!(
+                        "Creating keystore at {} for imported identity",
+                        path.display()
+                    );
+                    imported_contacts = import.contacts;
+                    import.identity_key
+                }
+                None => {
+                    info!(
+                        "No keystore found at {}, creating a new identity",
+                        path.display()
+                    );
+                    ed25519_dalek::Keypair::generate(&mut OsRng)
+                        .to_bytes()
+                        .to_vec()
+                }
+            };
+            // The X25519 key is never exported, peers start new sessions when they see
+            // the new key
+            let data = KeystoreData {
+                identity_key,
+                private_key: x25519_dalek::StaticSecret::new(&mut OsRng).to_bytes(),
+                prekeys: PrekeyStore::new(),
+                pins: KeyPins::new(),
+                chats: Vec::new(),
+                groups: Vec::new(),
+                previous_keys: Vec::new(),
+            };
+            (Keystore::create(&path, &self.passphrase, &data)?, data)
+        };
+        self.passphrase.clear();
+        let identity_key = ed25519_dalek::Keypair::from_bytes(&data.identity_key)
+            .map_err(|_| "The keystore contains an invalid identity key")?;
+        network::set_keys(
+            x25519_dalek::StaticSecret::from(data.private_key),
+            identity_key,
+        );
+        self.prekeys = data.prekeys;
+        self.pins = data.pins;
+        self.chats = data.chats;
+        self.groups = data.groups;
+        self.previous_keys = data.previous_keys;
+        // Needs the keys to be set to create the sessions
+        for contact in &imported_contacts {
+            self.pins.replace(&contact.id, contact.identity_key);
+            self.chats
+                .push((contact.id.clone(), Chat::from_contact(contact)));
+            self.keystore_dirty = true;
+        }
+        self.keystore = Some(keystore);
+        Ok(())
+    }
+
+    // Sessions advance with every message so this is done after every change,
+    // otherwise a restart would leave them out of sync with the peers
+    fn save_keystore(&mut self) {
+        if !self.keystore_dirty {
+            return;
+        }
+        if let Some(keystore) = &self.keystore {
+            let data = KeystoreData {
+                identity_key: network::identity_key().to_bytes().to_vec(),
+                private_key: network::private_key().to_bytes(),
+                prekeys: self.prekeys.clone(),
+                pins: self.pins.clone(),
+                chats: self.chats.clone(),
+                groups: self.groups.clone(),
+                previous_keys: self.previous_keys.clone(),
+            };
+            if let Err(err) = keystore.save(&data) {
+                error!("Failed to save keystore: {}", err);
+                self.command_line.show_error("Failed to save keystore");
+            }
+        }
+        self.keystore_dirty = false;
+    }
+
+    // Replaced signed prekeys are kept as long as the server keeps queued messages
+    // that may have been started from them
+    fn upload_prekeys(&mut self) {
+        if let Some(connection) = &self.connection {
+            let upload = self
+                .prekeys
+                .generate_upload(connection.server_info().queue_ttl);
+            if let Err(err) = connection.send(upload) {
+                error!("Failed to upload prekeys: {}", err);
+            }
+        }
+    }
+
+    // Messages are first tried with the existing session, messages carrying an initial
+    // header that it can't decrypt start a new session from our prekeys. A session that
+    // has already received messages is never replaced that way, otherwise replaying the
+    // first message of a conversation would reset it.
+    // `previous_key` is the index of the replaced key the envelope was sealed to, the
+    // sessions kept with that key are used instead of the ones in the chats.
+    fn decrypt_incoming(
+        &mut self,
+        encrypted: EncryptedMessage,
+        previous_key: Option<usize>,
+    ) -> Result<(Message, SequenceStatus)> {
+        let from = encrypted.get_info().0.clone();
+        let initial_header = encrypted.initial_header().cloned();
+        if let Some(session) = self.session_for(&from, previous_key) {
+            match session.decrypt(encrypted.clone()) {
+                Ok(decrypted) => return Ok(decrypted),
+                Err(err) if initial_header.is_none() || session.has_received() => return Err(err),
+                Err(_) => {}
+            }
+        }
+        let initial_header =
+            initial_header.ok_or_else(|| format!("Received message from unknown peer {}", from))?;
+        // Checked before pinning, x3dh::respond verifies it again
+        initial_header.sender_key.verify(&from)?;
+        if !self.check_pinned_key(&from, &initial_header.sender_key) {
+            return Err(format!("The identity key of {} changed", from).into());
+        }
+        let signed_prekey = self
+            .prekeys
+            .signed_prekey(initial_header.signed_prekey_id)
+            .ok_or("Message uses an unknown signed prekey")?;
+        if self.prekeys.is_used(
+            initial_header.signed_prekey_id,
+            &initial_header.ephemeral_key,
+        ) {
+            return Err(format!("Replayed initial message from {}", from).into());
+        }
+        let one_time_prekey = match initial_header.one_time_prekey_id {
+            Some(key_id) => Some(
+                self.prekeys
+                    .one_time_prekey(key_id)
+                    .ok_or("Message uses an unknown or already used one-time prekey")?,
+            ),
+            None => None,
+        };
+        let private_key = match previous_key {
+            Some(index) => StaticSecret::from(self.previous_keys[index].private_key),
+            None => network::private_key(),
+        };
+        let mut session = x3dh::respond(
+            &private_key,
+            PublicKey::from(&private_key).as_bytes(),
+            &signed_prekey,
+            one_time_prekey.as_ref(),
+            &from,
+            &initial_header,
+        )?;
+        let decrypted = session.decrypt(encrypted)?;
+        self.prekeys.mark_used(
+            initial_header.signed_prekey_id,
+            initial_header.ephemeral_key,
+        );
+        if let Some(key_id) = initial_header.one_time_prekey_id {
+            self.prekeys.remove_one_time_prekey(key_id);
+        }
+        let sender_key = &initial_header.sender_key;
+        if let Some(index) = previous_key {
+            // The peer replaces the session once it sees our key change
+            info!("{} started a new session with our previous key", from);
+            let sessions = &mut self.previous_keys[index].sessions;
+            sessions.retain(|(id, _)| *id != from);
+            sessions.push((from.clone(), session));
+            if self.get_chat_for(&from).is_none() {
+                let mut chat = Chat::new(sender_key);
+                chat.online = false;
+                self.chats.push((from.clone(), chat));
+                self.send_pending_group_keys(&from);
+            }
+        } else if let Some(chat) = self.get_chat_for(&from) {
+            info!("{} started a new session", from);
+            chat.restart(sender_key, session);
+        } else {
+            info!("{} started a chat", from);
+            // Peers that are online are already in the chat list
+            self.chats
+                .push((from.clone(), Chat::from_session(sender_key, session, false)));
+            self.send_pending_group_keys(&from);
+        }
+        Ok(decrypted)
+    }
+
+    // Bundles fetched to send a group key don't change the selected chat
+    fn session_for(&mut self, id: &str, previous_key: Option<usize>) -> Option<&mut Session> {
+        match previous_key {
+            Some(index) => self.previous_keys[index]
+                .sessions
+                .iter_mut()
+                .find(|(peer_id, _)| peer_id == id)
+                .map(|(_, session)| session),
+            None => self.get_chat_for(id).map(|chat| &mut chat.session),
+        }
+    }
+
+    fn start_chat_from_bundle(&mut self, id: String, bundle: PrekeyBundle) {
+        if self.get_chat_for(&id).is_some() {
+            self.send_pending_group_keys(&id);
+            return;
+        }
+        let own_key = network::signed_public_key(&self.id);
+        match x3dh::initiate(&network::private_key(), &own_key, &id, &bundle) {
+            Ok(session) => {
+                self.command_line.show_info_message(format!(
+                    "Started chat with {}, messages are delivered when they come online",
+                    id
+                ));
+                self.chats.push((
+                    id.clone(),
+                    Chat::from_session(&bundle.public_key, session, false),
+                ));
+                if !self
+                    .pending_group_keys
+                    .iter()
+                    .any(|(member, _)| *member == id)
+                {
+                    self.current_chat_index = Some(self.groups.len() + self.chats.len() - 1);
+                }
+                self.check_pinned_key(&id, &bundle.public_key);
+                self.send_pending_group_keys(&id);
+            }
+            Err(err) => {
+                error!("Invalid prekey bundle for {}: {}", id, err);
+                self.command_line
+                    .show_error(format!("Received invalid keys for {}", id));
+            }
+        }
+    }
+
+    // The complete list of peers that are online, received after registering
+    fn handle_peer_list(&mut self, peers: Vec<(String, SignedPublicKey)>) {
+        info!("Received peerlist of length {}", peers.len());
+        if self.get_current_route().id == RouteId::StartScreen {
+            // The peer list confirms that the registration was accepted
+            self.push_route(Route {
+                id: RouteId::Chat,
+                hovered_block: ActiveBlock::ChatList,
+                active_block: ActiveBlock::ChatList,
+            });
+            self.upload_prekeys();
+        }
+        self.command_line.show_info_message("Received peerlist");
+        self.remove_expired_keys();
+        // Replies to requests sent over the previous connection are lost
+        self.fetch_unconfirmed_keys();
+        let mut rejected_peers = 0;
+        // Chats from an earlier connection are kept so messages queued
+        // by the server while we were away can still be decrypted
+        for (_, chat) in self.chats.iter_mut() {
+            chat.online = false;
+        }
+        for (peer_id, signed_key) in peers {
+            if peer_id == self.id {
+                continue;
+            }
+            if let Err(err) = signed_key.verify(&peer_id) {
+                error!("Rejected public key for {}: {}", peer_id, err);
+                rejected_peers += 1;
+            } else {
+                self.update_peer_key(peer_id, signed_key);
+            }
+        }
+        if rejected_peers > 0 {
+            self.command_line.show_error(format!(
+                "Rejected {} peer(s) with invalid key signatures",
+                rejected_peers
+            ));
+        }
+    }
+
+    // Called with verified keys of peers that are online. Known contacts get the new key
+    // unless its identity key differs from the pinned one.
+    fn update_peer_key(&mut self, id: String, signed_key: SignedPublicKey) {
+        if self.get_chat_for(&id).is_none() {
+            info!("Adding peer {} to chat list", id);
+            self.chats.push((id.clone(), Chat::new(&signed_key)));
+        }
+        let accepted = self.check_pinned_key(&id, &signed_key);
+        let chat = self.get_chat_for(&id).expect("Chat to have been added");
+        chat.online = true;
+        if accepted {
+            chat.pending_key = None;
+            chat.change_key(&signed_key);
+        }
+    }
+
+    // Pins the identity key on first sight. A different key than the pinned one blocks
+    // the chat until the user accepts it with `:accept`, returns false in that case.
+    fn check_pinned_key(&mut self, id: &str, signed_key: &SignedPublicKey) -> bool {
+        let pinned = match self.pins.check(id, &signed_key.identity_key) {
+            Ok(()) => return true,
+            Err(pinned) => pinned,
+        };
+        warn!("Identity key of {} doesn't match the pinned key", id);
+        let notice = format!(
+            "The identity key of {} changed! Old fingerprint: {}, new fingerprint: {}. \
+             Run :accept to trust the new key",
+            id,
+            fingerprint::fingerprint(id, &pinned),
+            fingerprint::fingerprint(id, &signed_key.identity_key)
+        );
+        if let Some(chat) = self.get_chat_for(id) {
+            // Peers reconnecting with the same key shouldn't repeat the notice
+            if chat.pending_key.as_ref() != Some(signed_key) {
+                chat.messages.push(HistoryEntry::notice(notice));
+                chat.pending_key = Some(signed_key.clone());
+            }
+        }
+        self.command_line.show_warning(format!(
+            "The identity key of {} changed, sending is blocked until you run :accept",
+            id
+        ));
+        false
+    }
+
+    // How long the server keeps messages for offline peers, keys that queued messages
+    // may have been sealed to are kept as long
+    fn queue_ttl(&self) -> Option<u64> {
+        self.connection
+            .as_ref()
+            .map(|connection| connection.server_info().queue_ttl)
+    }
+
+    fn remove_expired_keys(&mut self) {
+        let queue_ttl = match self.queue_ttl() {
+            Some(queue_ttl) => queue_ttl,
+            None => return,
+        };
+        let now = keystore::unix_time();
+        let key_count = self.previous_keys.len();
+        self.previous_keys
+            .retain(|key| now.saturating_sub(key.replaced_at) < queue_ttl);
+        if self.previous_keys.len() < key_count {
+            self.keystore_dirty = true;
+            // The indexes of the remaining keys have changed
+            self.unconfirmed_messages
+                .retain(|(_, previous_key)| previous_key.is_none());
+        }
+    }
+
+    // The server has accepted the new key, switching to it any earlier would leave us
+    // out of sync with the server and the peers if it had been rejected
+    fn finish_key_rotation(&mut self, key_change: &KeyChange) {
+        let new_key = match self.pending_rotation.take() {
+            Some((_, new_key, pending)) if pending == *key_change => new_key,
+            other => {
+                self.pending_rotation = other;
+                return;
+            }
+        };
+        let private_key = network::private_key().to_bytes();
+        network::set_private_key(new_key);
+        // Peers start new sessions with the new key, ours have to be replaced the same way.
+        // The old ones are kept with the old key for messages that are already on the way.
+        let sessions = self
+            .chats
+            .iter_mut()
+            .map(|(id, chat)| {
+                let session = chat.session.clone();
+                chat.reset_session();
+                (id.clone(), session)
+            })
+            .collect();
+        self.previous_keys.push(PreviousKey {
+            private_key,
+            replaced_at: keystore::unix_time(),
+            sessions,
+        });
+        self.keystore_dirty = true;
+        self.command_line
+            .show_info_message("Rotated key, new sessions were started with every contact");
+    }
+
+    // Only accepted if it comes from the identity key we know for the peer and replaces
+    // the key we are using for it
+    fn handle_key_change(&mut self, id: String, key_change: KeyChange) {
+        if id == self.id {
+            // Our own announcement forwarded back by the server
+            self.finish_key_rotation(&key_change);
+            return;
+        }
+        let chat = match self.get_chat_for(&id) {
+            Some(chat) => chat,
+            None => {
+                info!("Ignoring key change from {} since there is no chat", id);
+                return;
+            }
+        };
+        let result = key_change.verify(&id).and_then(|_| {
+            if chat.pending_key.is_some() {
+                Err("The changed identity key hasn't been accepted yet".into())
+            } else if key_change.new_key.identity_key != chat.identity_key {
+                Err("It's signed by another identity key".into())
+            } else if key_change.previous_key != chat.public_key() {
+                Err("It doesn't replace the current key".into())
+            } else {
+                Ok(())
+            }
+        });
+        match result {
+            Ok(()) => {
+                info!("{} rotated its key", id);
+                chat.change_key(&key_change.new_key);
+                chat.messages.push(HistoryEntry::notice(format!(
+                    "{} rotated their key, started a new session",
+                    id
+                )));
+                self.command_line
+                    .show_info_message(format!("{} rotated their key", id));
+            }
+            Err(err) => {
+                error!("Rejected key change from {}: {}", id, err);
+                self.command_line
+                    .show_error(format!("Rejected key change from {}: {}", id, err));
+            }
+        }
+    }
+
+    // Sends the text to the selected chat or group and adds it to the history
+    pub(crate) fn send_text(&mut self, text: String) -> Result<()> {
+        let body = MessageBody::new(&MessageKind::Text(text.clone()))?;
+        if let Some(index) = self.current_group_position() {
+            let group = &mut self.groups[index];
+            let message = group.encrypt(&self.id, body.encode()?, &self.padding)?;
+            group.messages.push(HistoryEntry::sent(&body, text));
+            let recipients = group.recipients(&self.id);
+            let name = group.name.clone();
+            self.keystore_dirty = true;
+            let reference = self.track_sent_message(name, None);
+            return self
+                .connection
+                .as_ref()
+                .ok_or("Not connected")?
+                .send(ClientMessage::GroupMessage(reference, recipients, message));
+        }
+        let index = self
+            .current_chat_position()
+            .ok_or("Select a chat from the chat list first")?;
+        let (to, chat) = &mut self.chats[index];
+        chat.messages.push(HistoryEntry::sent(&body, text));
+        let history_id = Some(body.id);
+        let to = to.clone();
+        self.send_pairwise(&to, body, history_id)
+    }
+
+    // Encrypts with the pairwise session, `history_id` is the message in the chat
+    // history that is marked if the server can't deliver it
+    fn send_pairwise(
+        &mut self,
+        to: &str,
+        body: MessageBody,
+        history_id: Option<MessageId>,
+    ) -> Result<()> {
+        let message = Message::new(self.id.clone(), to.to_string(), body);
+        // Not `get_chat_for` since the padding policy is borrowed at the same time
+        let (_, chat) = self
+            .chats
+            .iter_mut()
+            .find(|(id, _)| id == to)
+            .ok_or_else(|| format!("No chat with {}", to))?;
+        if chat.pending_key.is_some() {
+            return Err(format!("The key of {} changed, run :accept first", to).into());
+        }
+        let encrypted = chat.session.encrypt(message, &self.padding)?;
+        // Hides the sender from the server
+        let envelope = SealedEnvelope::seal(
+            &encrypted,
+            &PublicKey::from(chat.public_key()),
+            &self.padding,
+        )?;
+        self.keystore_dirty = true;
+        let reference = self.track_sent_message(to.to_string(), history_id);
+        self.connection
+            .as_ref()
+            .ok_or("Not connected")?
+            .send(ClientMessage::Message(reference, envelope))
+    }
+
+    // The sender is only known once the envelope has been opened
+    fn receive_message(&mut self, envelope: SealedEnvelope) {
+        // Peers that haven't seen our latest key change still seal to an older key
+        let mut opened = envelope
+            .open(&network::private_key())
+            .map(|encrypted| (encrypted, None));
+        for (index, previous_key) in self.previous_keys.iter().enumerate().rev() {
+            if opened.is_ok() {
+                break;
+            }
+            opened = envelope
+                .open(&StaticSecret::from(previous_key.private_key))
+                .map(|encrypted| (encrypted, Some(index)));
+        }
+        let (encrypted, previous_key) = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                error!("Failed to open sealed message: {}", err);
+                self.command_line
+                    .show_error("Couldn't open a received message");
+                return;
+            }
+        };
+        let from = encrypted.get_info().0.clone();
+        // The server doesn't know who sealed the message, so nothing vouches for the
+        // key of a sender we haven't seen before but the sender itself
+        if encrypted.initial_header().is_some() && !self.pins.is_pinned(&from) {
+            self.hold_until_confirmed(encrypted, previous_key);
+            return;
+        }
+        self.read_message(encrypted, previous_key);
+    }
+
+    fn read_message(&mut self, encrypted: EncryptedMessage, previous_key: Option<usize>) {
+        let from = encrypted.get_info().0.clone();
+        if let Err(err) = self
+            .decrypt_incoming(encrypted, previous_key)
+            .and_then(|(incoming, status)| self.handle_incoming(&from, incoming.body, status))
+        {
+            error!("Failed to decrypt message from {}: {}", from, err);
+            self.command_line
+                .show_error(format!("Couldn't decrypt a message from {}", from));
+        }
+    }
+
+    fn hold_until_confirmed(&mut self, encrypted: EncryptedMessage, previous_key: Option<usize>) {
+        let from = encrypted.get_info().0.clone();
+        let fetching = self
+            .unconfirmed_messages
+            .iter()
+            .any(|(held, _)| *held.get_info().0 == from);
+        self.unconfirmed_messages
+            .push_back((encrypted, previous_key));
+        if self.unconfirmed_messages.len() > UNCONFIRMED_MESSAGES_MAX {
+            self.unconfirmed_messages.pop_front();
+        }
+        if !fetching {
+            info!("Confirming the key of {} with the server", from);
+            self.fetch_public_key(from);
+        }
+    }
+
+    fn fetch_unconfirmed_keys(&mut self) {
+        let mut senders = self
+            .unconfirmed_messages
+            .iter()
+            .map(|(held, _)| held.get_info().0.clone())
+            .collect::<Vec<String>>();
+        senders.sort();
+        senders.dedup();
+        for sender in senders {
+            self.fetch_public_key(sender);
+        }
+    }
+
+    fn fetch_public_key(&self, id: String) {
+        if let Some(connection) = &self.connection {
+            if let Err(err) = connection.send(ClientMessage::FetchPublicKey(id)) {
+                error!("Failed to request a public key: {}", err);
+            }
+        }
+    }
+
+    // Held messages are only read if they were started with the identity key bound to
+    // the sender's account, anyone else could have sealed them
+    fn confirm_sender(&mut self, id: String, signed_key: SignedPublicKey) {
+        let (held, rest): (VecDeque<_>, VecDeque<_>) =
+            std::mem::take(&mut self.unconfirmed_messages)
+                .into_iter()
+                .partition(|(encrypted, _)| *encrypted.get_info().0 == id);
+        self.unconfirmed_messages = rest;
+        if let Err(err) = signed_key.verify(&id) {
+            error!("Rejected public key for {}: {}", id, err);
+            self.command_line
+                .show_error(format!("Received invalid public key for {}", id));
+            return;
+        }
+        for (encrypted, previous_key) in held {
+            let claimed = encrypted
+                .initial_header()
+                .map(|header| header.sender_key.identity_key);
+            if claimed != Some(signed_key.identity_key) {
+                error!("Dropped a message from someone claiming to be {}", id);
+                self.command_line
+                    .show_error(format!("Dropped a message that claimed to be from {}", id));
+                continue;
+            }
+            self.read_message(encrypted, previous_key);
+        }
+    }
+
+    // `status` says where the message falls in the sequence of messages from the sender,
+    // gaps and late messages are pointed out in the chat history
+    fn handle_incoming(
+        &mut self,
+        from: &str,
+        body: MessageBody,
+        status: SequenceStatus,
+    ) -> Result<()> {
+        let content = body.kind()?;
+        if let SequenceStatus::Gap(missing) = status {
+            warn!("{} messages from {} are missing", missing, from);
+            if let Some(chat) = self.get_chat_for(from) {
+                chat.messages.push(HistoryEntry::notice(format!(
+                    "{} missing messages from {}",
+                    missing, from
+                )));
+            }
+        }
+        match content {
+            MessageKind::Text(text) => {
+                if let Some(chat) = self.get_chat_for(from) {
+                    chat.messages.push(HistoryEntry::received(
+                        from,
+                        &body,
+                        text,
+                        status == SequenceStatus::OutOfOrder,
+                    ));
+                }
+            }
+            MessageKind::GroupUpdate(update) => self.handle_group_update(from, update),
+        }
+        Ok(())
+    }
+
+    // Groups are listed before the chats, so adding one moves the selected chat down.
+    // Returns the index of the group in the chat list.
+    pub(crate) fn add_group(&mut self, group: Group) -> usize {
+        if let Some(index) = self.current_chat_index {
+            if index >= self.groups.len() {
+                self.current_chat_index = Some(index + 1);
+            }
+        }
+        self.groups.push(group);
+        let index = self.groups.len() - 1;
+        self.send_group_key(index);
+        self.keystore_dirty = true;
+        index
+    }
+
+    // Our sender key goes to every other member over the pairwise sessions. Members we
+    // have no chat with get it once their prekey bundle has been fetched.
+    fn send_group_key(&mut self, index: usize) {
+        let group = &self.groups[index];
+        let group_id = group.id;
+        let recipients = group.recipients(&self.id);
+        let body = match group
+            .update()
+            .and_then(|update| MessageBody::new(&MessageKind::GroupUpdate(update)))
+        {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Failed to create group update: {}", err);
+                return;
+            }
+        };
+        let mut missing = Vec::new();
+        for member in recipients {
+            if self.get_chat_for(&member).is_none() {
+                self.fetch_bundle_for_group_key(member, group_id);
+                continue;
+            }
+            if let Err(err) = self.send_pairwise(&member, body.clone(), None) {
+                error!("Failed to send group key to {}: {}", member, err);
+                missing.push(member);
+            }
+        }
+        if !missing.is_empty() {
+            self.command_line.show_warning(format!(
+                "Couldn't send the group key to {}",
+                missing.join(", ")
+            ));
+        }
+    }
+
+    fn fetch_bundle_for_group_key(&mut self, member: String, group_id: GroupId) {
+        let connection = match &self.connection {
+            Some(connection) => connection,
+            None => return,
+        };
+        // One fetch is enough for every group waiting for the member
+        let fetching = self
+            .pending_group_keys
+            .iter()
+            .any(|(pending, _)| *pending == member);
+        if !fetching {
+            info!("Fetching keys for {} to send a group key", member);
+            if let Err(err) = connection.send(ClientMessage::FetchPrekeyBundle(member.clone())) {
+                error!("Failed to request prekeys for {}: {}", member, err);
+                return;
+            }
+        }
+        if !self
+            .pending_group_keys
+            .contains(&(member.clone(), group_id))
+        {
+            self.pending_group_keys.push((member, group_id));
+        }
+    }
+
+    fn send_pending_group_keys(&mut self, member: &str) {
+        let (pending, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending_group_keys)
+            .into_iter()
+            .partition(|(pending, _)| pending == member);
+        self.pending_group_keys = rest;
+        for (_, group_id) in pending {
+            let body = match self
+                .groups
+                .iter()
+                .find(|group| group.id == group_id)
+                .ok_or_else(|| "The group no longer exists".into())
+                .and_then(Group::update)
+                .and_then(|update| MessageBody::new(&MessageKind::GroupUpdate(update)))
+            {
+                Ok(body) => body,
+                Err(err) => {
+                    error!("Failed to create group update: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = self.send_pairwise(member, body, None) {
+                error!("Failed to send group key to {}: {}", member, err);
+                self.command_line
+                    .show_warning(format!("Couldn't send the group key to {}", member));
+            }
+        }
+    }
+
+    // The first update for a group creates it and hands our own key to the other members,
+    // later ones only carry the keys of the members that joined after us
+    fn handle_group_update(&mut self, from: &str, update: GroupUpdate) {
+        if let Some(group) = self
+            .groups
+            .iter_mut()
+            .find(|group| group.id == update.group_id)
+        {
+            if group.members.iter().any(|member| member == from) {
+                group.set_member_key(from, update.sender_key);
+            } else {
+                warn!(
+                    "{} sent a key for {} without being a member",
+                    from, group.name
+                );
+            }
+            return;
+        }
+        let is_member = |id: &str| update.members.iter().any(|member| member == id);
+        if !is_member(from) || !is_member(&self.id) || update.members.len() > MAX_GROUP_MEMBERS {
+            warn!("Ignoring invalid group update from {}", from);
+            return;
+        }
+        info!("{} added us to the group {}", from, update.name);
+        self.command_line
+            .show_info_message(format!("{} added you to the group {}", from, update.name));
+        let mut group = Group::new(update.group_id, update.name, update.members);
+        group.set_member_key(from, update.sender_key);
+        self.add_group(group);
+    }
+
+    fn receive_group_message(&mut self, message: GroupMessage) {
+        let group = match self
+            .groups
+            .iter_mut()
+            .find(|group| group.id == *message.group_id())
+        {
+            Some(group) => group,
+            None => {
+                warn!("Group message from {} for an unknown group", message.from());
+                return;
+            }
+        };
+        match group
+            .decrypt(&message)
+            .and_then(|body| MessageBody::decode(&body))
+            .and_then(|body| Ok((body.kind()?, body)))
+        {
+            Ok((MessageKind::Text(text), body)) => {
+                group
+                    .messages
+                    .push(HistoryEntry::received(message.from(), &body, text, false));
+            }
+            Ok(_) => warn!("Unexpected group message content from {}", message.from()),
+            Err(err) => {
+                error!(
+                    "Failed to decrypt group message from {}: {}",
+                    message.from(),
+                    err
+                );
+                self.command_line.show_error(format!(
+                    "Couldn't decrypt a message from {} in {}",
+                    message.from(),
+                    group.name
+                ));
+            }
+        }
+    }
+
+    fn handle_server_error(&mut self, err: ServerError) {
+        error!("Server error: {}", err);
+        if err.code.is_registration_error() && self.get_current_route().id == RouteId::StartScreen {
+            self.command_line
+                .show_error(format!("Registration failed: {}", err));
+            // Drop the connection so the user can pick another id
+            self.connection = None;
+            return;
+        }
+        if let Some((reference, _, _)) = &self.pending_rotation {
+            if err.reference == Some(*reference) {
+                self.pending_rotation = None;
+                self.command_line
+                    .show_error(format!("The server rejected the key rotation: {}", err));
+                return;
+            }
+        }
+        let sent_message = err.reference.and_then(|reference| {
+            self.sent_messages
+                .iter()
+                .find(|(sent_reference, _, _)| *sent_reference == reference)
+                .cloned()
+        });
+        if let Some((_, to, history_id)) = sent_message {
+            if let Some(message) = history_id.and_then(|history_id| {
+                self.get_chat_for(&to)
+                    .and_then(|chat| history::find_message_mut(&mut chat.messages, history_id))
+            }) {
+                message.undelivered = true;
+            }
+            self.command_line
+                .show_error(format!("Message to {} wasn't delivered: {}", to, err));
+        } else {
+            self.command_line.show_error(err.to_string());
         }
     }
 
@@ -66,8 +1007,18 @@ impl App {
         }
     }
 
+    pub(crate) fn current_group_position(&self) -> Option<usize> {
+        self.current_chat_index
+            .filter(|index| *index < self.groups.len())
+    }
+
+    pub(crate) fn current_chat_position(&self) -> Option<usize> {
+        self.current_chat_index
+            .and_then(|index| index.checked_sub(self.groups.len()))
+    }
+
     pub(crate) fn get_current_chat(&mut self) -> Option<&mut Chat> {
-        if let Some(index) = self.current_chat_index {
+        if let Some(index) = self.current_chat_position() {
             Some(&mut self.chats[index].1)
         } else {
             None
@@ -94,7 +1045,7 @@ impl App {
         active_block: Option<ActiveBlock>,
         hovered_block: Option<ActiveBlock>,
     ) {
-        let mut current_route = self.get_current_route_mut();
+        let current_route = self.get_current_route_mut();
         if let Some(active_block) = active_block {
             current_route.active_block = active_block;
         }
@@ -114,6 +1065,7 @@ pub enum RouteId {
 pub enum ActiveBlock {
     Empty,
     Id,
+    Passphrase,
     ChatWindow,
     ChatList,
     CommandLine,
@@ -126,6 +1078,18 @@ pub struct Route {
     pub hovered_block: ActiveBlock,
 }
 
+// Set `ENCRYPTER_PADDING` to a comma separated list of bucket sizes to use other
+// buckets than the default ones
+fn padding_policy() -> PaddingPolicy {
+    match env::var("ENCRYPTER_PADDING") {
+        Ok(buckets) => buckets.parse().unwrap_or_else(|err| {
+            warn!("Ignoring invalid ENCRYPTER_PADDING {}: {}", buckets, err);
+            PaddingPolicy::default()
+        }),
+        Err(_) => PaddingPolicy::default(),
+    }
+}
+
 fn main() -> Result<()> {
     let _ = WriteLogger::init(
         LevelFilter::Info,
@@ -145,81 +1109,97 @@ fn main() -> Result<()> {
     let mut app = App::new();
     loop {
         if let Some(ref mut connection) = app.connection {
-            if let Some(protocol_message) = connection.step()? {
-                match protocol_message {
-                    Protocol::Message(encrypted_incoming) => {
-                        let (from, _to) = encrypted_incoming.get_info();
-                        if let Some(chat) = app.get_chat_for(from) {
-                            let incoming = encrypted_incoming.decrypt_message(&chat.shared_key);
-                            if let Some(chat) = app.get_chat_for(&incoming.from) {
-                                chat.messages.push(format!(
-                                    "{}: {}",
-                                    incoming.from,
-                                    String::from_utf8_lossy(&incoming.content)
-                                ));
-                            }
-                        } else {
-                            error!(
-                                "Missing decryption key and/or peer in chatlist, peer: {}",
-                                from
-                            );
-                            app.command_line
-                                .show_error(format!("Received message from unknown peer {}", from));
-                            todo!("Handle this error in a better way");
-                        }
+            // Only sending fails, the message is dropped and the connection can still be used
+            let network_event = match connection.step() {
+                Ok(network_event) => network_event,
+                Err(err) => {
+                    error!("Failed to send message: {}", err);
+                    app.command_line
+                        .show_error(format!("Couldn't send message: {}", err));
+                    None
+                }
+            };
+            if let Some(network_event) = network_event {
+                // Most events change chats, sessions or prekeys
+                app.keystore_dirty = true;
+                match network_event {
+                    NetworkEvent::Message(ServerMessage::Message(envelope)) => {
+                        app.receive_message(envelope);
+                    }
+                    NetworkEvent::Message(ServerMessage::GroupMessage(message)) => {
+                        app.receive_group_message(message);
                     }
-                    Protocol::PeerList(peers) => {
-                        info!("Received peerlist of length {}", peers.len());
-                        app.command_line.show_info_message("Received peerlist");
-                        app.chats = peers
-                            .into_iter()
-                            .filter(|(peer_id, _)| peer_id != &app.id)
-                            .map(|(peer_id, public_key_buffer)| {
-                                (peer_id, Chat::new(public_key_buffer))
-                            })
-                            .collect::<Vec<(String, Chat)>>();
+                    NetworkEvent::Message(ServerMessage::PrekeyBundle(id, bundle)) => {
+                        app.start_chat_from_bundle(id, *bundle);
                     }
-                    Protocol::Disconnect(id) => {
+                    NetworkEvent::Message(ServerMessage::PublicKey(id, signed_key)) => {
+                        app.confirm_sender(id, signed_key);
+                    }
+                    NetworkEvent::Message(ServerMessage::KeyChange(id, key_change)) => {
+                        app.handle_key_change(id, key_change);
+                    }
+                    NetworkEvent::Message(ServerMessage::PeerList(peers, complete)) => {
+                        app.peer_list.extend(peers);
+                        if complete {
+                            let peers = std::mem::take(&mut app.peer_list);
+                            app.handle_peer_list(peers);
+                        }
+                    }
+                    NetworkEvent::Message(ServerMessage::Disconnect(id)) => {
                         let log = format!("Received disconnect for: {}", id);
                         info!("{}", log);
                         app.command_line.show_info_message(log);
-                        if let Some(index) =
-                            app.chats.iter().position(|(peer_id, _)| peer_id == &id)
-                        {
-                            if let Some(current_index) = app.current_chat_index {
-                                if index == current_index {
-                                    app.current_chat_index = None;
-                                }
-                            }
-                            info!("Removed chat for {}", id);
-                            app.chats.remove(index);
+                        // The chat is kept, messages sent while the peer is offline
+                        // are queued by the server
+                        if let Some(chat) = app.get_chat_for(&id) {
+                            chat.online = false;
                         }
                     }
-                    Protocol::NewConnection(id, public_key) => {
+                    NetworkEvent::Message(ServerMessage::NewConnection(id, signed_key)) => {
                         info!("Received connection to new peer: {}", id);
-                        app.command_line
-                            .show_info_message(format!("New connection to: {}", id));
-                        if let Some((_, chat)) =
-                            app.chats.iter_mut().find(|(old_id, _)| old_id == &id)
-                        {
-                            warn!("Peer already in chat list, updating public_key");
-                            chat.change_key(public_key);
+                        if let Err(err) = signed_key.verify(&id) {
+                            error!("Rejected public key for {}: {}", id, err);
+                            app.command_line
+                                .show_error(format!("Received invalid public key for {}", id));
                         } else {
-                            info!("Adding peer {} to chat list", id);
-                            app.chats.push((id, Chat::new(public_key)));
+                            app.command_line
+                                .show_info_message(format!("New connection to: {}", id));
+                            app.update_peer_key(id, signed_key);
                         }
                     }
-                    Protocol::ConnectionLost => {
-                        app.command_line.show_error("Lost server connection!")
+                    NetworkEvent::Message(ServerMessage::Challenge(challenge)) => {
+                        // Proves to the server that we own the identity key bound to the id
+                        let signature =
+                            identity::sign_challenge(&app.id, &challenge, network::identity_key());
+                        if let Err(err) =
+                            connection.send(ClientMessage::ChallengeResponse(signature))
+                        {
+                            error!("Failed to answer the login challenge: {}", err);
+                            app.command_line.show_error(format!(
+                                "Couldn't answer the login challenge: {}",
+                                err
+                            ));
+                        }
                     }
-                    unknown_message => {
+                    NetworkEvent::Message(handshake_message @ ServerMessage::Welcome(_)) => {
                         app.command_line
-                            .show_warning("Received a message client can't handle");
+                            .show_warning("Received a handshake message after connecting");
                         warn!(
-                            "Received a message client can't handle: {:?}",
-                            unknown_message
+                            "Received a handshake message after connecting: {:?}",
+                            handshake_message
                         )
                     }
+                    NetworkEvent::Message(ServerMessage::Error(err)) => {
+                        app.handle_server_error(err);
+                    }
+                    NetworkEvent::ConnectionLost => {
+                        app.command_line.show_error("Lost server connection!");
+                        app.peer_list.clear();
+                        if app.get_current_route().id == RouteId::StartScreen {
+                            // Lost before the registration completed, allow trying again
+                            app.connection = None;
+                        }
+                    }
                 }
             }
         }
@@ -249,10 +1229,14 @@ fn main() -> Result<()> {
         // stdout is buffered, flush it to see the effect immediately when hitting backspace
         std::io::stdout().flush().ok();
 
+        app.save_keystore();
+
         // Handle input
         if let Event::Input(input) = events.next().unwrap() {
             match input {
                 Key::Ctrl('c') => {
+                    app.keystore_dirty = true;
+                    app.save_keystore();
                     break;
                 }
                 _ => {
@@ -264,3 +1248,100 @@ fn main() -> Result<()> {
     //app.net_thread_scope.unwrap();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Keypair;
+
+    fn seal(session: &mut Session, from: &str, text: &str, to: &PublicKey) -> SealedEnvelope {
+        let body = MessageBody::new(&MessageKind::Text(text.to_string())).unwrap();
+        let message = Message::new(from.to_string(), "alice".to_string(), body);
+        let encrypted = session.encrypt(message, &PaddingPolicy::default()).unwrap();
+        SealedEnvelope::seal(&encrypted, to, &PaddingPolicy::default()).unwrap()
+    }
+
+    fn history(app: &mut App, id: &str) -> Vec<String> {
+        app.get_chat_for(id)
+            .expect("Chat to exist")
+            .messages
+            .iter()
+            .map(|entry| match entry {
+                HistoryEntry::Message(message) => message.text.clone(),
+                HistoryEntry::Notice(notice) => notice.clone(),
+            })
+            .collect()
+    }
+
+    fn signed_key(id: &str) -> (StaticSecret, SignedPublicKey) {
+        let secret = StaticSecret::new(&mut OsRng);
+        let identity = Keypair::generate(&mut OsRng);
+        let signed_key = SignedPublicKey::sign(id, &PublicKey::from(&secret), &identity);
+        (secret, signed_key)
+    }
+
+    // Our keys are global, so everything that needs them is in this test
+    #[test]
+    fn reads_messages_from_confirmed_senders_across_key_rotation() {
+        let old_key = StaticSecret::new(&mut OsRng);
+        let old_public = PublicKey::from(&old_key);
+        network::set_keys(old_key, Keypair::generate(&mut OsRng));
+        let mut app = App::new();
+        app.id = "alice".to_string();
+
+        // Bob is online and uses the session derived from our keys
+        let (bob_key, bob_signed) = signed_key("bob");
+        app.update_peer_key("bob".to_string(), bob_signed);
+        let mut bob_session = Session::new(&bob_key, old_public);
+        let before = seal(&mut bob_session, "bob", "before", &old_public);
+
+        // Carol starts a chat from a prekey bundle fetched before the rotation, and
+        // Mallory pretends to be Dave
+        let (signed_prekey, mut one_time_prekeys) = match app.prekeys.generate_upload(60) {
+            ClientMessage::UploadPrekeys(signed_prekey, one_time_prekeys) => {
+                (signed_prekey, one_time_prekeys)
+            }
+            _ => unreachable!(),
+        };
+        let mut bundle = || PrekeyBundle {
+            public_key: network::signed_public_key("alice"),
+            signed_prekey: signed_prekey.clone(),
+            one_time_prekey: one_time_prekeys.pop(),
+        };
+        let (carol_key, carol_signed) = signed_key("carol");
+        let mut carol_session =
+            x3dh::initiate(&carol_key, &carol_signed, "alice", &bundle()).unwrap();
+        let from_carol = seal(&mut carol_session, "carol", "hello", &old_public);
+        let (mallory_key, mallory_signed) = signed_key("dave");
+        let mut mallory_session =
+            x3dh::initiate(&mallory_key, &mallory_signed, "alice", &bundle()).unwrap();
+        let from_mallory = seal(&mut mallory_session, "dave", "it's me", &old_public);
+
+        let new_key = StaticSecret::new(&mut OsRng);
+        let new_public = PublicKey::from(&new_key);
+        let key_change =
+            KeyChange::sign("alice", &old_public, &new_public, network::identity_key());
+        app.pending_rotation = Some((0, new_key, key_change.clone()));
+        app.finish_key_rotation(&key_change);
+        assert_eq!(
+            PublicKey::from(&network::private_key()).as_bytes(),
+            new_public.as_bytes()
+        );
+
+        app.receive_message(before);
+        app.receive_message(from_carol);
+        app.receive_message(from_mallory);
+        // Held until the server has confirmed the keys
+        assert!(app.get_chat_for("carol").is_none());
+        app.confirm_sender("carol".to_string(), carol_signed);
+        app.confirm_sender("dave".to_string(), signed_key("dave").1);
+        assert!(app.get_chat_for("dave").is_none());
+        assert!(app.unconfirmed_messages.is_empty());
+        // Bob has seen the key change and started a new session
+        let mut bob_session = Session::new(&bob_key, new_public);
+        app.receive_message(seal(&mut bob_session, "bob", "after", &new_public));
+
+        assert_eq!(history(&mut app, "bob"), vec!["before", "after"]);
+        assert_eq!(history(&mut app, "carol"), vec!["hello"]);
+    }
+}
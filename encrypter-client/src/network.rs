@@ -1,31 +1,89 @@
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use encrypter_core::{Protocol, Result, MESSAGE_PACKET_SIZE};
-use once_cell::sync::Lazy;
-use rand::rngs::OsRng;
+use ed25519_dalek::Keypair;
+use encrypter_core::framing::{encode_frame, FrameDecoder, FrameError};
+use encrypter_core::handshake::{self, ClientHello, ServerHello};
+use encrypter_core::identity::SignedPublicKey;
+use encrypter_core::{ClientMessage, Result, ServerMessage};
+use once_cell::sync::OnceCell;
 use std::io::{BufReader, Read, Write};
-use std::net::TcpStream;
 use std::net::ToSocketAddrs;
+use std::net::{Shutdown, TcpStream};
+use std::sync::RwLock;
+use std::time::Duration;
 use x25519_dalek::{PublicKey, StaticSecret};
 
-pub(crate) static PRIVATE_KEY: Lazy<StaticSecret> = Lazy::new(|| {
-    let mut seed = OsRng::default();
-    StaticSecret::new(&mut seed)
-});
-static PUBLIC_KEY: Lazy<PublicKey> = Lazy::new(|| PublicKey::from(&*PRIVATE_KEY));
+const READ_BUFFER_SIZE: usize = 4096;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Both keys are loaded from the keystore once the user has entered the passphrase,
+// the X25519 key can be replaced later with `:rotate`
+static PRIVATE_KEY: OnceCell<RwLock<StaticSecret>> = OnceCell::new();
+// Long-term identity key, used to sign the X25519 key so peers can tell it hasn't
+// been substituted by the server. It's generated once when the keystore is created
+// and read back from it on every start, a new identity key would look like a key
+// change to every peer that has pinned or verified the old one.
+static IDENTITY_KEY: OnceCell<Keypair> = OnceCell::new();
+
+pub(crate) fn set_keys(private_key: StaticSecret, identity_key: Keypair) {
+    if PRIVATE_KEY.set(RwLock::new(private_key)).is_err() || IDENTITY_KEY.set(identity_key).is_err()
+    {
+        panic!("Keys can only be loaded once");
+    }
+}
+
+pub(crate) fn private_key() -> StaticSecret {
+    PRIVATE_KEY
+        .get()
+        .expect("Keystore to be unlocked")
+        .read()
+        .expect("Private key lock to not be poisoned")
+        .clone()
+}
+
+pub(crate) fn set_private_key(private_key: StaticSecret) {
+    *PRIVATE_KEY
+        .get()
+        .expect("Keystore to be unlocked")
+        .write()
+        .expect("Private key lock to not be poisoned") = private_key;
+}
+
+pub(crate) fn identity_key() -> &'static Keypair {
+    IDENTITY_KEY.get().expect("Keystore to be unlocked")
+}
+
+// Our X25519 key signed for `id`, as sent to the server and to peers
+pub(crate) fn signed_public_key(id: &str) -> SignedPublicKey {
+    SignedPublicKey::sign(id, &PublicKey::from(&private_key()), identity_key())
+}
+
+/// What the listener thread passes on to the main loop
+#[derive(Debug)]
+pub enum NetworkEvent {
+    Message(ServerMessage),
+    ConnectionLost,
+}
 
 #[derive(Debug)]
 pub struct ServerConnection {
-    outgoing_sender: Sender<Protocol>,
-    outgoing_receiver: Receiver<Protocol>,
-    incoming_receiver: Receiver<Protocol>,
-    incoming_sender: Sender<Protocol>,
+    outgoing_sender: Sender<ClientMessage>,
+    outgoing_receiver: Receiver<ClientMessage>,
+    incoming_receiver: Receiver<NetworkEvent>,
+    incoming_sender: Sender<NetworkEvent>,
     stream: TcpStream,
+    server_hello: ServerHello,
     //  thread_handle: thread::JoinHandle fixa de här sen
 }
 
 impl ServerConnection {
     pub fn new(server_addr: impl ToSocketAddrs, id: String) -> Result<Self> {
-        let stream = TcpStream::connect(&server_addr)?;
+        let mut stream = TcpStream::connect(&server_addr)?;
+        let mut decoder = FrameDecoder::new();
+        let server_hello = handshake(&mut stream, &mut decoder)?;
+        info!(
+            "Connected to {} on protocol version {}",
+            server_hello.server_name, server_hello.protocol_version
+        );
         let (incoming_sender, incoming_receiver) = unbounded();
         let (outgoing_sender, outgoing_receiver) = unbounded();
         let connection = ServerConnection {
@@ -34,53 +92,78 @@ impl ServerConnection {
             incoming_receiver,
             incoming_sender,
             stream,
+            server_hello,
         };
-        connection.send(Protocol::NewConnection(id, *PUBLIC_KEY.as_bytes()))?;
-        connection.server_connection_loop()?;
+        let signed_key = signed_public_key(&id);
+        connection.send(ClientMessage::NewConnection(id, signed_key))?;
+        connection.server_connection_loop(decoder)?;
         Ok(connection)
     }
 
-    fn server_connection_loop(&self) -> Result<()> {
+    pub fn server_info(&self) -> &ServerHello {
+        &self.server_hello
+    }
+
+    // Takes over the decoder used during the handshake in case the server
+    // already sent more than the handshake reply
+    fn server_connection_loop(&self, mut decoder: FrameDecoder) -> Result<()> {
         let reader = self.stream.try_clone().unwrap();
         let sender = self.incoming_sender.clone();
         std::thread::spawn(move || {
             let mut reader = BufReader::new(reader);
-            let mut buffer = vec![0 as u8; MESSAGE_PACKET_SIZE];
-            loop {
+            let mut buffer = vec![0_u8; READ_BUFFER_SIZE];
+            'read: loop {
                 match reader.read(&mut buffer) {
-                    Ok(0) | Err(_) => {
-                        error!("Server connection lost!");
-                        sender
-                            .send(Protocol::ConnectionLost)
-                            .expect("Failed to send connection lost message");
-                        break;
-                    }
-                    Ok(n) => match bincode::deserialize::<Protocol>(&buffer[..n]) {
-                        Ok(message) => {
-                            sender
-                                .send(message)
-                                .expect("Failed to sennd message from tcp listener thread");
+                    Ok(0) | Err(_) => break 'read,
+                    Ok(n) => {
+                        decoder.extend(&buffer[..n]);
+                        loop {
+                            match decoder.decode::<ServerMessage>() {
+                                Ok(Some(message)) => {
+                                    if sender.send(NetworkEvent::Message(message)).is_err() {
+                                        // The connection has been dropped by the app
+                                        return;
+                                    }
+                                }
+                                Ok(None) => break,
+                                Err(err @ FrameError::Malformed(_)) => {
+                                    error!(
+                                        "Could not parse message from incomming traffic {}",
+                                        err
+                                    );
+                                }
+                                Err(err @ FrameError::TooLarge(_)) => {
+                                    error!("Server stream is out of sync: {}", err);
+                                    break 'read;
+                                }
+                            }
                         }
-                        Err(err) => {
-                            error!("Could not parse message from incomming traffic {}", err);
-                        }
-                    },
+                    }
                 }
             }
+            error!("Server connection lost!");
+            // Fails if the connection was dropped by the app, nobody to notify then
+            let _ = sender.send(NetworkEvent::ConnectionLost);
         });
         Ok(())
     }
 
-    pub fn send(&self, message: Protocol) -> Result<()> {
+    pub fn send(&self, message: ClientMessage) -> Result<()> {
         self.outgoing_sender.send(message)?;
         Ok(())
     }
 
-    pub fn step(&mut self) -> Result<Option<Protocol>> {
+    pub fn step(&mut self) -> Result<Option<NetworkEvent>> {
         if let Ok(outgoing) = self.outgoing_receiver.try_recv() {
-            let message = &mut bincode::serialize(&outgoing)?;
-            debug_assert!(message.len() <= MESSAGE_PACKET_SIZE);
-            self.stream.write_all(&message)?;
+            let frame = encode_frame(&outgoing)?;
+            if frame.len() > self.server_hello.max_frame_size as usize {
+                return Err(format!(
+                    "Message of {} bytes exceeds the server's max frame size",
+                    frame.len()
+                )
+                .into());
+            }
+            self.stream.write_all(&frame)?;
         }
         if let Ok(msg_from_server) = self.incoming_receiver.try_recv() {
             return Ok(Some(msg_from_server));
@@ -88,3 +171,41 @@ impl ServerConnection {
         Ok(None)
     }
 }
+
+impl Drop for ServerConnection {
+    // The listener thread holds a clone of the stream so it has
+    // to be shut down explicitly to close the connection
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+// Sends the client hello and blocks until the server has replied,
+// this is done before the listener thread is started.
+fn handshake(stream: &mut TcpStream, decoder: &mut FrameDecoder) -> Result<ServerHello> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.write_all(&encode_frame(&ClientMessage::Hello(ClientHello::new()))?)?;
+    let mut buffer = vec![0_u8; READ_BUFFER_SIZE];
+    let server_hello = loop {
+        if let Some(reply) = decoder.decode::<ServerMessage>()? {
+            break match reply {
+                ServerMessage::Welcome(server_hello) => {
+                    handshake::check_version(server_hello.protocol_version)?;
+                    server_hello
+                }
+                ServerMessage::Error(err) => {
+                    return Err(format!("Server rejected the connection: {}", err).into());
+                }
+                unexpected => {
+                    return Err(format!("Unexpected handshake reply: {:?}", unexpected).into());
+                }
+            };
+        }
+        match stream.read(&mut buffer)? {
+            0 => return Err("Server closed the connection during the handshake".into()),
+            n => decoder.extend(&buffer[..n]),
+        }
+    };
+    stream.set_read_timeout(None)?;
+    Ok(server_hello)
+}
@@ -0,0 +1,42 @@
+use encrypter_core::identity::IDENTITY_KEY_SIZE;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Identity keys of every contact, pinned the first time the contact is seen
+/// (trust on first use). Saved in the keystore so the pins survive restarts.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub(crate) struct KeyPins {
+    pins: HashMap<String, [u8; IDENTITY_KEY_SIZE]>,
+}
+
+impl KeyPins {
+    pub fn new() -> Self {
+        KeyPins::default()
+    }
+
+    /// Pins the key if nothing is pinned for the id yet. Returns the pinned key
+    /// as the error if it differs from `identity_key`.
+    pub fn check(
+        &mut self,
+        id: &str,
+        identity_key: &[u8; IDENTITY_KEY_SIZE],
+    ) -> Result<(), [u8; IDENTITY_KEY_SIZE]> {
+        match self.pins.get(id) {
+            Some(pinned) if pinned != identity_key => Err(*pinned),
+            Some(_) => Ok(()),
+            None => {
+                self.pins.insert(id.to_string(), *identity_key);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn is_pinned(&self, id: &str) -> bool {
+        self.pins.contains_key(id)
+    }
+
+    // Only called once the user has accepted the new key
+    pub fn replace(&mut self, id: &str, identity_key: [u8; IDENTITY_KEY_SIZE]) {
+        self.pins.insert(id.to_string(), identity_key);
+    }
+}
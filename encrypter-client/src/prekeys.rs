@@ -0,0 +1,187 @@
+use crate::keystore::unix_time;
+use crate::network;
+use encrypter_core::x3dh::{OneTimePrekey, SignedPrekey};
+use encrypter_core::ClientMessage;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// Number of one-time prekeys uploaded every time the client registers
+const ONE_TIME_PREKEY_BATCH: usize = 20;
+
+/// Secret halves of the prekeys uploaded to the server. Replaced signed prekeys are
+/// kept for a grace period so conversations started from an earlier bundle can still
+/// be read, after that they are deleted for forward secrecy.
+/// Secrets are stored as bytes so the store can be saved in the keystore.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub(crate) struct PrekeyStore {
+    next_key_id: u32,
+    signed_prekeys: HashMap<u32, SignedPrekeySecret>,
+    // The batch the server has now
+    one_time_prekeys: HashMap<u32, [u8; 32]>,
+    // The batch the server had before the last upload. The server drops its unused
+    // keys on upload, but messages started from keys it handed out may still be queued.
+    previous_one_time_prekeys: HashMap<u32, [u8; 32]>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SignedPrekeySecret {
+    secret: [u8; 32],
+    // Unix time in seconds when a newer signed prekey was uploaded
+    replaced_at: Option<u64>,
+    // Ephemeral keys of the sessions started with this prekey, an initial message
+    // using one of them again is a replay
+    used_ephemeral_keys: HashSet<[u8; 32]>,
+}
+
+impl PrekeyStore {
+    pub fn new() -> Self {
+        PrekeyStore::default()
+    }
+
+    // Generates a new signed prekey and a batch of one-time prekeys to replace
+    // the ones on the server, prekeys that can't be used anymore are deleted.
+    // Replaced signed prekeys are kept for `grace_secs`.
+    pub fn generate_upload(&mut self, grace_secs: u64) -> ClientMessage {
+        self.remove_expired_prekeys(grace_secs);
+        let signed_key_id = self.next_key_id();
+        let signed_secret = StaticSecret::new(&mut OsRng);
+        let signed_prekey = SignedPrekey::sign(
+            signed_key_id,
+            &PublicKey::from(&signed_secret),
+            network::identity_key(),
+        );
+        self.signed_prekeys.insert(
+            signed_key_id,
+            SignedPrekeySecret {
+                secret: signed_secret.to_bytes(),
+                replaced_at: None,
+                used_ephemeral_keys: HashSet::new(),
+            },
+        );
+
+        let one_time_prekeys = (0..ONE_TIME_PREKEY_BATCH)
+            .map(|_| {
+                let key_id = self.next_key_id();
+                let secret = StaticSecret::new(&mut OsRng);
+                let prekey = OneTimePrekey {
+                    key_id,
+                    public_key: *PublicKey::from(&secret).as_bytes(),
+                };
+                self.one_time_prekeys.insert(key_id, secret.to_bytes());
+                prekey
+            })
+            .collect();
+        ClientMessage::UploadPrekeys(signed_prekey, one_time_prekeys)
+    }
+
+    pub fn signed_prekey(&self, key_id: u32) -> Option<StaticSecret> {
+        self.signed_prekeys
+            .get(&key_id)
+            .map(|signed_prekey| StaticSecret::from(signed_prekey.secret))
+    }
+
+    pub fn is_used(&self, signed_key_id: u32, ephemeral_key: &[u8; 32]) -> bool {
+        self.signed_prekeys
+            .get(&signed_key_id)
+            .is_some_and(|signed_prekey| signed_prekey.used_ephemeral_keys.contains(ephemeral_key))
+    }
+
+    // Called once a session has been started from an initial message
+    pub fn mark_used(&mut self, signed_key_id: u32, ephemeral_key: [u8; 32]) {
+        if let Some(signed_prekey) = self.signed_prekeys.get_mut(&signed_key_id) {
+            signed_prekey.used_ephemeral_keys.insert(ephemeral_key);
+        }
+    }
+
+    pub fn one_time_prekey(&self, key_id: u32) -> Option<StaticSecret> {
+        self.one_time_prekeys
+            .get(&key_id)
+            .or_else(|| self.previous_one_time_prekeys.get(&key_id))
+            .map(|secret| StaticSecret::from(*secret))
+    }
+
+    // One-time prekeys are deleted once used so a replayed initial message can't be read
+    pub fn remove_one_time_prekey(&mut self, key_id: u32) {
+        self.one_time_prekeys.remove(&key_id);
+        self.previous_one_time_prekeys.remove(&key_id);
+    }
+
+    // Called before a new batch replaces the prekeys on the server
+    fn remove_expired_prekeys(&mut self, grace_secs: u64) {
+        self.previous_one_time_prekeys = std::mem::take(&mut self.one_time_prekeys);
+        let now = unix_time();
+        self.signed_prekeys
+            .retain(|_, signed_prekey| match signed_prekey.replaced_at {
+                Some(replaced_at) => now.saturating_sub(replaced_at) < grace_secs,
+                None => {
+                    signed_prekey.replaced_at = Some(now);
+                    true
+                }
+            });
+    }
+
+    fn next_key_id(&mut self) -> u32 {
+        let key_id = self.next_key_id;
+        self.next_key_id = self.next_key_id.wrapping_add(1);
+        key_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_with_signed_prekey(key_id: u32) -> PrekeyStore {
+        let mut store = PrekeyStore::new();
+        store.signed_prekeys.insert(
+            key_id,
+            SignedPrekeySecret {
+                secret: StaticSecret::new(&mut OsRng).to_bytes(),
+                replaced_at: None,
+                used_ephemeral_keys: HashSet::new(),
+            },
+        );
+        store
+    }
+
+    #[test]
+    fn detects_replayed_ephemeral_keys() {
+        let mut store = store_with_signed_prekey(1);
+        assert!(!store.is_used(1, &[1; 32]));
+        store.mark_used(1, [1; 32]);
+        assert!(store.is_used(1, &[1; 32]));
+        assert!(!store.is_used(1, &[2; 32]));
+        // Only for the signed prekey the session was started with
+        assert!(!store.is_used(2, &[1; 32]));
+    }
+
+    #[test]
+    fn keeps_replaced_signed_prekeys_for_grace_period() {
+        let mut store = store_with_signed_prekey(1);
+        store.mark_used(1, [1; 32]);
+        // The first upload after it was created replaces it
+        store.remove_expired_prekeys(0);
+        assert!(store.signed_prekey(1).is_some());
+        store.remove_expired_prekeys(60);
+        assert!(store.signed_prekey(1).is_some());
+        assert!(store.is_used(1, &[1; 32]));
+        store.remove_expired_prekeys(0);
+        assert!(store.signed_prekey(1).is_none());
+    }
+
+    #[test]
+    fn keeps_previous_one_time_prekeys_for_one_upload() {
+        let mut store = PrekeyStore::new();
+        store.one_time_prekeys.insert(1, [1; 32]);
+        store.remove_expired_prekeys(0);
+        assert!(store.one_time_prekey(1).is_some());
+        store.remove_expired_prekeys(0);
+        assert!(store.one_time_prekey(1).is_none());
+
+        store.one_time_prekeys.insert(2, [2; 32]);
+        store.remove_one_time_prekey(2);
+        assert!(store.one_time_prekey(2).is_none());
+    }
+}
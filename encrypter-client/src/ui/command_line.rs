@@ -16,9 +16,67 @@ enum DisplayMode {
     Default,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    SafetyNumber,
+    Verify,
+    Unverify,
+    // Starts a conversation with a peer that might be offline
+    Chat(String),
+    // Path and passphrase of an exported identity, the passphrase is asked for
+    // separately so it isn't shown or logged
+    Export(String, String),
+    Import(String, String),
+    // Replaces our X25519 key
+    RotateKey,
+    // Trusts the changed identity key of the current chat
+    AcceptKey,
+    // Name and members of a new group
+    Group(String, Vec<String>),
+}
+
+impl Command {
+    fn parse(command: &str) -> Result<Self, String> {
+        let mut words = command.split_whitespace();
+        match words.next() {
+            Some("safety") => Ok(Command::SafetyNumber),
+            Some("verify") => Ok(Command::Verify),
+            Some("unverify") => Ok(Command::Unverify),
+            Some("rotate") => Ok(Command::RotateKey),
+            Some("accept") => Ok(Command::AcceptKey),
+            Some("group") => match words.next() {
+                Some(name) => Ok(Command::Group(
+                    name.to_string(),
+                    words.map(String::from).collect(),
+                )),
+                None => Err(String::from("Usage: :group <name> <member>...")),
+            },
+            Some("chat") => match words.next() {
+                Some(id) => Ok(Command::Chat(id.to_string())),
+                None => Err(String::from("Usage: :chat <id>")),
+            },
+            Some("export") => match words.next() {
+                Some(path) => Ok(Command::Export(path.to_string(), String::new())),
+                None => Err(String::from("Usage: :export <path>")),
+            },
+            Some("import") => match words.next() {
+                Some(path) => Ok(Command::Import(path.to_string(), String::new())),
+                None => Err(String::from("Usage: :import <path>")),
+            },
+            Some(unknown) => Err(format!("Unknown command: {}", unknown)),
+            None => Err(String::from("Empty command")),
+        }
+    }
+}
+
 pub struct CommandLine {
     content: String,
     display_mode: DisplayMode,
+    pending_command: Option<Command>,
+    // Export or import waiting for its passphrase, which is typed into `passphrase`
+    // and only shown masked
+    passphrase_prompt: Option<Command>,
+    passphrase: String,
 }
 
 impl CommandLine {
@@ -26,6 +84,9 @@ impl CommandLine {
         CommandLine {
             content: String::from("Commandline"),
             display_mode: DisplayMode::Default,
+            pending_command: None,
+            passphrase_prompt: None,
+            passphrase: String::new(),
         }
     }
 
@@ -67,8 +128,60 @@ impl CommandLine {
         }
     }
 
-    fn handle_command(&self, command: String) {
+    fn handle_command(&mut self, command: String) {
         info!("A command was sent! {}", command);
+        match Command::parse(&command) {
+            Ok(command @ Command::Export(..)) | Ok(command @ Command::Import(..)) => {
+                self.passphrase_prompt = Some(command);
+                self.display_mode = DisplayMode::Input;
+                self.update_prompt();
+            }
+            Ok(command) => self.pending_command = Some(command),
+            Err(err) => self.show_error(err),
+        }
+    }
+
+    fn update_prompt(&mut self) {
+        self.content = format!(
+            "Passphrase: {}",
+            "*".repeat(self.passphrase.chars().count())
+        );
+    }
+
+    fn handle_passphrase_event(&mut self, input_key: Key) {
+        match input_key {
+            Key::Char('\n') => {
+                let passphrase = std::mem::take(&mut self.passphrase);
+                self.pending_command = match self.passphrase_prompt.take() {
+                    Some(Command::Export(path, _)) => Some(Command::Export(path, passphrase)),
+                    Some(Command::Import(path, _)) => Some(Command::Import(path, passphrase)),
+                    _ => None,
+                };
+                self.content.clear();
+                self.display_mode = DisplayMode::Default;
+                return;
+            }
+            Key::Char(c) => self.passphrase.push(c),
+            Key::Backspace => {
+                self.passphrase.pop();
+            }
+            _ => {}
+        }
+        self.update_prompt();
+    }
+
+    // Called when the user leaves the command line
+    pub fn cancel(&mut self) {
+        if self.passphrase_prompt.take().is_some() {
+            self.passphrase.clear();
+            self.content.clear();
+            self.display_mode = DisplayMode::Default;
+        }
+    }
+
+    // Commands need access to the rest of the app so they are executed by the event handlers
+    pub fn take_command(&mut self) -> Option<Command> {
+        self.pending_command.take()
     }
 
     pub fn show_error<S: AsRef<str>>(&mut self, error: S) {
@@ -99,6 +212,10 @@ impl StatefulWidget for CommandLine {
 
     fn handle_event(&mut self, input_key: Key) {
         self.display_mode = DisplayMode::Input;
+        if self.passphrase_prompt.is_some() {
+            self.handle_passphrase_event(input_key);
+            return;
+        }
         match input_key {
             Key::Char(':') => {
                 self.content.clear();
@@ -108,8 +225,8 @@ impl StatefulWidget for CommandLine {
                 // skip first ':' sign
                 let command = self.content.drain(1..).collect::<String>();
                 self.content.pop();
-                self.handle_command(command);
                 self.display_mode = DisplayMode::Default;
+                self.handle_command(command);
             }
             Key::Char(c) => {
                 self.content.push(c);
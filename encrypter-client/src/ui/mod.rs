@@ -76,14 +76,52 @@ where
         "You need to select someone from the chat list before writing a message!"
     };
 
-    if let Some(chat) = app.get_current_chat() {
-        List::new(chat.messages.iter().map(Text::raw))
+    if let Some(index) = app.current_group_position() {
+        let group = &app.groups[index];
+        let title = format!(
+            "Group {} with {}",
+            group.name,
+            group.recipients(&app.id).join(", ")
+        );
+        let history = group
+            .messages
+            .iter()
+            .map(|entry| Text::raw(entry.to_string()));
+        List::new(history)
             .block(
                 Block::default()
                     .title_style(get_color(highlight_state))
                     .border_style(get_color(highlight_state))
                     .borders(Borders::ALL)
-                    .title("Messages"),
+                    .title(&title),
+            )
+            .render(frame, chunks[0]);
+    } else if let Some(index) = app.current_chat_position() {
+        let (peer_id, chat) = &app.chats[index];
+        let title = if chat.pending_key.is_some() {
+            format!(
+                "Messages with {} (KEY CHANGED, run :accept to trust the new key)",
+                peer_id
+            )
+        } else if chat.verified {
+            format!("Messages with {} (verified)", peer_id)
+        } else {
+            format!(
+                "Messages with {} (unverified, compare :safety and run :verify)",
+                peer_id
+            )
+        };
+        let history = chat
+            .messages
+            .iter()
+            .map(|entry| Text::raw(entry.to_string()));
+        List::new(history)
+            .block(
+                Block::default()
+                    .title_style(get_color(highlight_state))
+                    .border_style(get_color(highlight_state))
+                    .borders(Borders::ALL)
+                    .title(&title),
             )
             .render(frame, chunks[0]);
     }
@@ -107,11 +145,18 @@ where
         current_route.active_block == ActiveBlock::ChatList,
         current_route.hovered_block == ActiveBlock::ChatList,
     );
-    let contacts = app
-        .chats
-        .iter()
-        .map(|(user, _)| user)
-        .collect::<Vec<&String>>();
+    let groups = app.groups.iter().map(|group| format!("#{}", group.name));
+    let contacts = app.chats.iter().map(|(user, chat)| {
+        if chat.pending_key.is_some() {
+            format!("{} (key changed)", user)
+        } else if chat.online {
+            user.clone()
+        } else {
+            format!("{} (offline)", user)
+        }
+    });
+    // Groups come first, see `App::current_chat_index`
+    let contacts = groups.chain(contacts).collect::<Vec<String>>();
     SelectableList::default()
         .block(
             Block::default()
@@ -136,12 +181,17 @@ where
         current_route.active_block == ActiveBlock::Id,
         current_route.hovered_block == ActiveBlock::Id,
     );
+    let highlight_passphrase_state = (
+        current_route.active_block == ActiveBlock::Passphrase,
+        current_route.hovered_block == ActiveBlock::Passphrase,
+    );
 
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(2)
         .constraints(
             [
+                Constraint::Length(3),
                 Constraint::Length(3),
                 Constraint::Length(3),
                 Constraint::Min(3),
@@ -159,4 +209,16 @@ where
                 .title("Id"),
         )
         .render(frame, chunks[0]);
+    let masked_passphrase = "*".repeat(app.passphrase.chars().count());
+    Paragraph::new([Text::raw(&masked_passphrase)].iter())
+        .block(
+            Block::default()
+                .title_style(get_color(highlight_passphrase_state))
+                .border_style(get_color(highlight_passphrase_state))
+                .borders(Borders::ALL)
+                .title("Keystore passphrase"),
+        )
+        .render(frame, chunks[1]);
+    // Connection, keystore and registration errors are shown below the passphrase
+    app.command_line.draw(frame, chunks[2]);
 }
@@ -0,0 +1,115 @@
+use crate::group::MAX_GROUP_MEMBERS;
+use crate::ID_MAX_SIZE;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Client chosen number sent together with a message so errors caused
+/// by it can be tied back to it.
+pub type MessageReference = u32;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    HandshakeRequired,
+    MalformedFrame,
+    RateLimited,
+    InvalidId,
+    InvalidKey,
+    IdTaken,
+    NotRegistered,
+    Unauthorized,
+    UnknownRecipient,
+    QueueFull,
+    Internal,
+    ChallengeFailed,
+    NoPrekeys,
+    TooManyRecipients,
+}
+
+impl ErrorCode {
+    /// Errors that mean the registration was refused and the connection can't be used
+    pub fn is_registration_error(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::InvalidId
+                | ErrorCode::InvalidKey
+                | ErrorCode::IdTaken
+                | ErrorCode::ChallengeFailed
+        )
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::UnsupportedVersion => write!(f, "Unsupported protocol version"),
+            ErrorCode::HandshakeRequired => {
+                write!(f, "Connection must start with a handshake")
+            }
+            ErrorCode::MalformedFrame => write!(f, "The server couldn't parse a message"),
+            ErrorCode::RateLimited => write!(f, "Too many messages, slow down"),
+            ErrorCode::InvalidId => write!(
+                f,
+                "Ids must be 1 to {} characters without whitespace",
+                ID_MAX_SIZE
+            ),
+            ErrorCode::InvalidKey => write!(f, "The public key signature is invalid"),
+            ErrorCode::IdTaken => write!(f, "The id is already taken"),
+            ErrorCode::NotRegistered => write!(f, "The connection isn't registered"),
+            ErrorCode::Unauthorized => write!(f, "Not allowed for this connection"),
+            ErrorCode::UnknownRecipient => write!(f, "Unknown recipient"),
+            ErrorCode::QueueFull => write!(f, "Too many messages waiting for the recipient"),
+            ErrorCode::Internal => write!(f, "Internal server error"),
+            ErrorCode::ChallengeFailed => {
+                write!(f, "Couldn't prove ownership of the account's identity key")
+            }
+            ErrorCode::NoPrekeys => write!(f, "The peer hasn't uploaded any prekeys"),
+            ErrorCode::TooManyRecipients => write!(
+                f,
+                "Group messages can't have more than {} recipients",
+                MAX_GROUP_MEMBERS - 1
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    /// Extra human readable information, may be empty
+    pub details: String,
+    /// Set if the error was caused by a message sent by the client
+    pub reference: Option<MessageReference>,
+}
+
+impl ServerError {
+    pub fn new(code: ErrorCode) -> Self {
+        ServerError {
+            code,
+            details: String::new(),
+            reference: None,
+        }
+    }
+
+    pub fn with_details<S: Into<String>>(mut self, details: S) -> Self {
+        self.details = details.into();
+        self
+    }
+
+    pub fn with_reference(mut self, reference: MessageReference) -> Self {
+        self.reference = Some(reference);
+        self
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.details.is_empty() {
+            write!(f, "{}", self.code)
+        } else {
+            write!(f, "{}: {}", self.code, self.details)
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
@@ -0,0 +1,57 @@
+use crate::identity::IDENTITY_KEY_SIZE;
+use sha2::{Digest, Sha512};
+
+// Makes brute forcing an identity key with a colliding fingerprint more expensive
+const ITERATIONS: usize = 5200;
+const FINGERPRINT_VERSION: u16 = 0;
+const DIGIT_GROUPS: usize = 6;
+const DIGITS_PER_GROUP: usize = 5;
+
+/// A 30 digit fingerprint of an identity key, split into groups of 5 digits.
+/// Computed by iterating SHA-512 over the key and the peer id so it is bound to both.
+pub fn fingerprint(id: &str, identity_key: &[u8; IDENTITY_KEY_SIZE]) -> String {
+    let mut hash = {
+        let mut hasher = Sha512::new();
+        hasher.update(FINGERPRINT_VERSION.to_be_bytes());
+        hasher.update(identity_key);
+        hasher.update(id.as_bytes());
+        hasher.finalize()
+    };
+    for _ in 0..ITERATIONS {
+        let mut hasher = Sha512::new();
+        hasher.update(hash);
+        hasher.update(identity_key);
+        hash = hasher.finalize();
+    }
+    hash.chunks_exact(5)
+        .take(DIGIT_GROUPS)
+        .map(|chunk| {
+            let value = chunk
+                .iter()
+                .fold(0_u64, |value, &byte| (value << 8) | byte as u64);
+            format!(
+                "{:0width$}",
+                value % 10_u64.pow(DIGITS_PER_GROUP as u32),
+                width = DIGITS_PER_GROUP
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// The safety number of a conversation is both parties' fingerprints concatenated
+/// in a fixed order, so both sides see the same 60 digits and can compare them out of band.
+pub fn safety_number(
+    own_id: &str,
+    own_identity_key: &[u8; IDENTITY_KEY_SIZE],
+    peer_id: &str,
+    peer_identity_key: &[u8; IDENTITY_KEY_SIZE],
+) -> String {
+    let own = fingerprint(own_id, own_identity_key);
+    let peer = fingerprint(peer_id, peer_identity_key);
+    if own <= peer {
+        format!("{} {}", own, peer)
+    } else {
+        format!("{} {}", peer, own)
+    }
+}
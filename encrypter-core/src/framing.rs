@@ -0,0 +1,137 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+/// Largest frame payload either side is willing to receive, anything larger
+/// is treated as a protocol violation since the stream can't be trusted after that.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+const LENGTH_PREFIX_SIZE: usize = std::mem::size_of::<u32>();
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// The length prefix announced a frame larger than `MAX_FRAME_SIZE`.
+    /// The stream is out of sync after this and should be closed.
+    TooLarge(usize),
+    /// A complete frame was received but it couldn't be deserialized,
+    /// the frame has been consumed so the stream can still be read from.
+    Malformed(bincode::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge(size) => write!(
+                f,
+                "Frame of {} bytes exceeds max frame size of {} bytes",
+                size, MAX_FRAME_SIZE
+            ),
+            FrameError::Malformed(err) => write!(f, "Malformed frame: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Serializes the message and prefixes it with its length as a big endian u32
+pub fn encode_frame<T: Serialize>(message: &T) -> crate::Result<Vec<u8>> {
+    let payload = bincode::serialize(message)?;
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(Box::new(FrameError::TooLarge(payload.len())));
+    }
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Reassembles frames from a byte stream. Bytes are fed in with `extend` as they are read
+/// from the socket, regardless of how TCP split or coalesced them, and complete frames
+/// are taken out with `decode`. It doesn't do any io itself so it can be used both by
+/// the async server and the blocking client.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder { buffer: Vec::new() }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete frame or None if more bytes are needed.
+    /// Should be called repeatedly after each `extend` since a single read may contain
+    /// several frames.
+    pub fn decode<T: DeserializeOwned>(&mut self) -> Result<Option<T>, FrameError> {
+        if self.buffer.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+        let mut length_prefix = [0; LENGTH_PREFIX_SIZE];
+        length_prefix.copy_from_slice(&self.buffer[..LENGTH_PREFIX_SIZE]);
+        let frame_size = u32::from_be_bytes(length_prefix) as usize;
+        if frame_size > MAX_FRAME_SIZE {
+            return Err(FrameError::TooLarge(frame_size));
+        }
+        if self.buffer.len() < LENGTH_PREFIX_SIZE + frame_size {
+            return Ok(None);
+        }
+        let frame = self
+            .buffer
+            .drain(..LENGTH_PREFIX_SIZE + frame_size)
+            .skip(LENGTH_PREFIX_SIZE)
+            .collect::<Vec<u8>>();
+        bincode::deserialize(&frame)
+            .map(Some)
+            .map_err(FrameError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_frame_fed_byte_by_byte() {
+        let frame = encode_frame(&String::from("hello")).unwrap();
+        let mut decoder = FrameDecoder::new();
+        for byte in &frame[..frame.len() - 1] {
+            decoder.extend(&[*byte]);
+            assert!(decoder.decode::<String>().unwrap().is_none());
+        }
+        decoder.extend(&frame[frame.len() - 1..]);
+        assert_eq!(
+            decoder.decode::<String>().unwrap(),
+            Some(String::from("hello"))
+        );
+        assert!(decoder.decode::<String>().unwrap().is_none());
+    }
+
+    #[test]
+    fn decodes_two_frames_from_one_read() {
+        let mut bytes = encode_frame(&1u32).unwrap();
+        bytes.extend(encode_frame(&2u32).unwrap());
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes);
+        assert_eq!(decoder.decode::<u32>().unwrap(), Some(1));
+        assert_eq!(decoder.decode::<u32>().unwrap(), Some(2));
+        assert!(decoder.decode::<u32>().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_oversized_length_prefix() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&((MAX_FRAME_SIZE + 1) as u32).to_be_bytes());
+        match decoder.decode::<u32>() {
+            Err(FrameError::TooLarge(size)) => assert_eq!(size, MAX_FRAME_SIZE + 1),
+            other => panic!("Expected TooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_to_encode_oversized_message() {
+        assert!(encode_frame(&vec![0u8; MAX_FRAME_SIZE]).is_err());
+    }
+}
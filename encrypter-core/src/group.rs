@@ -0,0 +1,366 @@
+use crate::identity;
+use crate::keys::KEY_SIZE;
+use crate::padding::{self, PaddingPolicy};
+use crate::ratchet::{kdf_chain, ChainKey, MessageKey, MAX_SKIP};
+use crate::{Result, NONCE_SIZE};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Keypair, Signer};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Max number of members in a group, including the creator
+pub const MAX_GROUP_MEMBERS: usize = 32;
+pub const GROUP_ID_SIZE: usize = 16;
+const GROUP_MESSAGE_LABEL: &[u8] = b"encrypter group message v1";
+
+/// Random id chosen by the creator of the group
+pub type GroupId = [u8; GROUP_ID_SIZE];
+
+pub fn new_group_id() -> GroupId {
+    let mut group_id = [0; GROUP_ID_SIZE];
+    OsRng.fill_bytes(&mut group_id);
+    group_id
+}
+
+/// Sent to every member over the pairwise sessions when a member joins a group,
+/// it carries the membership and the sender's key for the group.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct GroupUpdate {
+    pub group_id: GroupId,
+    pub name: String,
+    /// Every member including the sender
+    pub members: Vec<String>,
+    pub sender_key: SenderKeyDistribution,
+}
+
+/// The current state of a member's sender key, enough for the receiver to decrypt
+/// every message the member sends from now on but none of the earlier ones.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct SenderKeyDistribution {
+    pub key_id: u32,
+    pub iteration: u32,
+    pub chain_key: [u8; KEY_SIZE],
+    /// Ed25519 key the member signs its group messages with, since every member
+    /// knows the chain key it can't be used to tell the members apart
+    pub signing_key: [u8; 32],
+}
+
+/// Our own sender key for a group. Every group message is encrypted once with a message
+/// key from the chain, so one ciphertext can be fanned out to all members by the server.
+/// Serializing it includes the secret keys, the output must be stored encrypted.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SenderKey {
+    key_id: u32,
+    iteration: u32,
+    chain_key: ChainKey,
+    /// Ed25519 keypair bytes, secret key followed by the public key
+    signing_key: Vec<u8>,
+}
+
+impl SenderKey {
+    pub fn new() -> Self {
+        let mut chain_key = [0; KEY_SIZE];
+        OsRng.fill_bytes(&mut chain_key);
+        SenderKey {
+            key_id: OsRng.next_u32(),
+            iteration: 0,
+            chain_key,
+            signing_key: Keypair::generate(&mut OsRng).to_bytes().to_vec(),
+        }
+    }
+
+    pub fn distribution(&self) -> Result<SenderKeyDistribution> {
+        Ok(SenderKeyDistribution {
+            key_id: self.key_id,
+            iteration: self.iteration,
+            chain_key: self.chain_key,
+            signing_key: self.signing_keypair()?.public.to_bytes(),
+        })
+    }
+
+    pub fn encrypt(
+        &mut self,
+        group_id: GroupId,
+        from: &str,
+        mut content: Vec<u8>,
+        padding: &PaddingPolicy,
+    ) -> Result<GroupMessage> {
+        let signing_keypair = self.signing_keypair()?;
+        let (chain_key, message_key) = kdf_chain(&self.chain_key);
+        let mut nonce = [0; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        padding.pad(&mut content);
+        let mut message = GroupMessage {
+            group_id,
+            from: from.to_string(),
+            key_id: self.key_id,
+            iteration: self.iteration,
+            nonce,
+            ciphertext: Vec::new(),
+            signature: Vec::new(),
+        };
+        message.ciphertext = ChaCha20Poly1305::new(&Key::from(message_key))
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &content,
+                    aad: &message.associated_data(),
+                },
+            )
+            .map_err(|_| "Failed to encrypt group message")?;
+        message.signature = signing_keypair
+            .sign(&message.signed_data())
+            .to_bytes()
+            .to_vec();
+        self.chain_key = chain_key;
+        self.iteration += 1;
+        Ok(message)
+    }
+
+    fn signing_keypair(&self) -> Result<Keypair> {
+        Keypair::from_bytes(&self.signing_key).map_err(|_| "Invalid sender signing key".into())
+    }
+}
+
+impl Default for SenderKey {
+    fn default() -> Self {
+        SenderKey::new()
+    }
+}
+
+/// Another member's sender key, advanced as its messages arrive
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReceivedSenderKey {
+    key_id: u32,
+    iteration: u32,
+    chain_key: ChainKey,
+    signing_key: [u8; 32],
+    // Keys of messages that were skipped over, so they can still be read if they
+    // arrive out of order
+    skipped_keys: VecDeque<(u32, MessageKey)>,
+}
+
+impl ReceivedSenderKey {
+    /// Checks the signature and decrypts the message, the key is left untouched
+    /// if anything fails.
+    pub fn decrypt(&mut self, message: &GroupMessage) -> Result<Vec<u8>> {
+        identity::verify_signature(
+            &self.signing_key,
+            &message.signed_data(),
+            &message.signature,
+        )
+        .map_err(|_| format!("Invalid signature on group message from {}", message.from))?;
+        if message.key_id != self.key_id {
+            return Err(format!("Unknown sender key from {}", message.from).into());
+        }
+        let mut next_state = self.clone();
+        let message_key = next_state.message_key(message.iteration)?;
+        let mut content = ChaCha20Poly1305::new(&Key::from(message_key))
+            .decrypt(
+                &Nonce::from(message.nonce),
+                Payload {
+                    msg: &message.ciphertext,
+                    aad: &message.associated_data(),
+                },
+            )
+            .map_err(|_| "Group message authentication failed")?;
+        padding::unpad(&mut content)?;
+        *self = next_state;
+        Ok(content)
+    }
+
+    fn message_key(&mut self, iteration: u32) -> Result<MessageKey> {
+        if iteration < self.iteration {
+            let position = self
+                .skipped_keys
+                .iter()
+                .position(|(skipped, _)| *skipped == iteration)
+                .ok_or("Group message key has already been used")?;
+            let (_, message_key) = self.skipped_keys.remove(position).expect("Key to exist");
+            return Ok(message_key);
+        }
+        if iteration - self.iteration > MAX_SKIP {
+            return Err("Too many skipped group messages".into());
+        }
+        while self.iteration < iteration {
+            let (chain_key, message_key) = kdf_chain(&self.chain_key);
+            self.skipped_keys.push_back((self.iteration, message_key));
+            if self.skipped_keys.len() > MAX_SKIP as usize {
+                self.skipped_keys.pop_front();
+            }
+            self.chain_key = chain_key;
+            self.iteration += 1;
+        }
+        let (chain_key, message_key) = kdf_chain(&self.chain_key);
+        self.chain_key = chain_key;
+        self.iteration += 1;
+        Ok(message_key)
+    }
+}
+
+impl From<SenderKeyDistribution> for ReceivedSenderKey {
+    fn from(distribution: SenderKeyDistribution) -> Self {
+        ReceivedSenderKey {
+            key_id: distribution.key_id,
+            iteration: distribution.iteration,
+            chain_key: distribution.chain_key,
+            signing_key: distribution.signing_key,
+            skipped_keys: VecDeque::new(),
+        }
+    }
+}
+
+/// A message encrypted with the sender's key for the group. The same ciphertext is
+/// delivered to every member, the group id and sender travel in plaintext but are
+/// covered by both the AEAD tag and the signature.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct GroupMessage {
+    group_id: GroupId,
+    from: String,
+    key_id: u32,
+    iteration: u32,
+    nonce: [u8; NONCE_SIZE],
+    ciphertext: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl GroupMessage {
+    pub fn group_id(&self) -> &GroupId {
+        &self.group_id
+    }
+
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn associated_data(&self) -> Vec<u8> {
+        let mut aad =
+            Vec::with_capacity(GROUP_MESSAGE_LABEL.len() + GROUP_ID_SIZE + 12 + self.from.len());
+        aad.extend_from_slice(GROUP_MESSAGE_LABEL);
+        aad.extend_from_slice(&self.group_id);
+        aad.extend_from_slice(&(self.from.len() as u32).to_be_bytes());
+        aad.extend_from_slice(self.from.as_bytes());
+        aad.extend_from_slice(&self.key_id.to_be_bytes());
+        aad.extend_from_slice(&self.iteration.to_be_bytes());
+        aad
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = self.associated_data();
+        data.extend_from_slice(&self.nonce);
+        data.extend_from_slice(&self.ciphertext);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GROUP_ID: GroupId = [1; GROUP_ID_SIZE];
+
+    fn encrypt(sender_key: &mut SenderKey, text: &str) -> GroupMessage {
+        sender_key
+            .encrypt(
+                GROUP_ID,
+                "alice",
+                text.as_bytes().to_vec(),
+                &PaddingPolicy::default(),
+            )
+            .unwrap()
+    }
+
+    fn received(sender_key: &SenderKey) -> ReceivedSenderKey {
+        ReceivedSenderKey::from(sender_key.distribution().unwrap())
+    }
+
+    // Advances the chain as if the messages had been sent
+    fn skip(sender_key: &mut SenderKey, count: u32) {
+        for _ in 0..count {
+            sender_key.chain_key = kdf_chain(&sender_key.chain_key).0;
+            sender_key.iteration += 1;
+        }
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let mut sender_key = SenderKey::new();
+        let mut receiver = received(&sender_key);
+        for text in &["first", "second"] {
+            let message = encrypt(&mut sender_key, text);
+            assert_eq!(message.group_id(), &GROUP_ID);
+            assert_eq!(message.from(), "alice");
+            assert_eq!(receiver.decrypt(&message).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn decrypts_out_of_order_once() {
+        let mut sender_key = SenderKey::new();
+        let mut receiver = received(&sender_key);
+        let first = encrypt(&mut sender_key, "first");
+        let second = encrypt(&mut sender_key, "second");
+        let third = encrypt(&mut sender_key, "third");
+        assert_eq!(receiver.decrypt(&third).unwrap(), b"third");
+        assert_eq!(receiver.decrypt(&first).unwrap(), b"first");
+        assert_eq!(receiver.decrypt(&second).unwrap(), b"second");
+        assert!(receiver.decrypt(&first).is_err());
+        assert!(receiver.decrypt(&third).is_err());
+    }
+
+    #[test]
+    fn only_decrypts_messages_after_distribution() {
+        let mut sender_key = SenderKey::new();
+        let earlier = encrypt(&mut sender_key, "earlier");
+        let mut receiver = received(&sender_key);
+        assert!(receiver.decrypt(&earlier).is_err());
+        let later = encrypt(&mut sender_key, "later");
+        assert_eq!(receiver.decrypt(&later).unwrap(), b"later");
+    }
+
+    #[test]
+    fn rejects_too_many_skipped_messages() {
+        let mut sender_key = SenderKey::new();
+        let mut receiver = received(&sender_key);
+        skip(&mut sender_key, MAX_SKIP + 1);
+        let message = encrypt(&mut sender_key, "too far");
+        assert!(receiver.decrypt(&message).is_err());
+
+        let mut sender_key = SenderKey::new();
+        let mut receiver = received(&sender_key);
+        skip(&mut sender_key, MAX_SKIP);
+        let message = encrypt(&mut sender_key, "just in reach");
+        assert_eq!(receiver.decrypt(&message).unwrap(), b"just in reach");
+    }
+
+    #[test]
+    fn rejects_invalid_signature() {
+        let mut sender_key = SenderKey::new();
+        let mut receiver = received(&sender_key);
+        let mut message = encrypt(&mut sender_key, "signed");
+        message.signature[0] ^= 1;
+        assert!(receiver.decrypt(&message).is_err());
+
+        // A member that knows the chain key can't sign as the sender
+        let mut forged = encrypt(&mut sender_key, "forged");
+        let impostor = Keypair::generate(&mut OsRng);
+        forged.signature = impostor.sign(&forged.signed_data()).to_bytes().to_vec();
+        assert!(receiver.decrypt(&forged).is_err());
+
+        // The key is left untouched by the failures
+        let message = encrypt(&mut sender_key, "after");
+        assert_eq!(receiver.decrypt(&message).unwrap(), b"after");
+    }
+
+    #[test]
+    fn rejects_unknown_key_id() {
+        let mut sender_key = SenderKey::new();
+        let mut receiver = received(&sender_key);
+        receiver.key_id = receiver.key_id.wrapping_add(1);
+        let message = encrypt(&mut sender_key, "new key");
+        assert!(receiver.decrypt(&message).is_err());
+    }
+}
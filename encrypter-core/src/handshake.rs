@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+
+/// Bumped on every breaking change to the wire format, that includes adding, removing
+/// or reordering variants of `ClientMessage` and `ServerMessage` since bincode encodes
+/// variants by their index.
+pub const PROTOCOL_VERSION: u16 = 17;
+/// Oldest protocol version this build can still talk to. Version 1 was sent both before
+/// and after `Protocol` was split into `ClientMessage` and `ServerMessage`, so builds
+/// reporting it can't be told apart and this must never go back to 1.
+pub const MIN_PROTOCOL_VERSION: u16 = 17;
+/// Optional features this build supports, advertised during the handshake.
+/// Strings rather than an enum so unknown features from newer peers can still be parsed.
+pub const SUPPORTED_FEATURES: &[&str] = &[
+    "signed-keys",
+    "double-ratchet",
+    "x3dh",
+    "key-rotation",
+    "groups",
+    "sealed-sender",
+];
+
+/// First message sent by a client after connecting
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct ClientHello {
+    pub protocol_version: u16,
+    pub features: Vec<String>,
+}
+
+impl ClientHello {
+    pub fn new() -> Self {
+        ClientHello {
+            protocol_version: PROTOCOL_VERSION,
+            features: supported_features(),
+        }
+    }
+}
+
+impl Default for ClientHello {
+    fn default() -> Self {
+        ClientHello::new()
+    }
+}
+
+/// The server's reply to an accepted `ClientHello`
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct ServerHello {
+    pub protocol_version: u16,
+    pub features: Vec<String>,
+    pub max_frame_size: u32,
+    /// Seconds messages for offline peers are kept, clients keep replaced keys as long
+    /// since queued messages may still need them
+    pub queue_ttl: u64,
+    pub server_name: String,
+    pub motd: String,
+}
+
+pub fn supported_features() -> Vec<String> {
+    SUPPORTED_FEATURES
+        .iter()
+        .map(|feature| feature.to_string())
+        .collect()
+}
+
+/// Checks if a peer speaking `version` can be talked to, the error is meant to be
+/// shown to the user.
+pub fn check_version(version: u16) -> Result<(), String> {
+    if version < MIN_PROTOCOL_VERSION {
+        Err(format!(
+            "Protocol version {} is no longer supported, the oldest supported version is {}",
+            version, MIN_PROTOCOL_VERSION
+        ))
+    } else if version > PROTOCOL_VERSION {
+        Err(format!(
+            "Protocol version {} is newer than the latest supported version {}",
+            version, PROTOCOL_VERSION
+        ))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use crate::Result;
+use ed25519_dalek::{Keypair, PublicKey as IdentityPublicKey, Signature, Signer};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use x25519_dalek::PublicKey;
+
+pub const IDENTITY_KEY_SIZE: usize = 32;
+pub const CHALLENGE_SIZE: usize = 32;
+const SIGNED_KEY_LABEL: &[u8] = b"encrypter signed x25519 key v1";
+const CHALLENGE_LABEL: &[u8] = b"encrypter login challenge v1";
+const KEY_CHANGE_LABEL: &[u8] = b"encrypter key change v1";
+
+/// An X25519 public key together with an Ed25519 signature from the owner's long-term
+/// identity key. The signature covers the peer id as well so the server can't hand out
+/// someone's signed key under a different id.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct SignedPublicKey {
+    pub identity_key: [u8; IDENTITY_KEY_SIZE],
+    pub public_key: [u8; 32],
+    signature: Vec<u8>,
+}
+
+impl SignedPublicKey {
+    pub fn sign(id: &str, public_key: &PublicKey, identity: &Keypair) -> Self {
+        let signature = identity.sign(&signed_data(id, public_key.as_bytes()));
+        SignedPublicKey {
+            identity_key: identity.public.to_bytes(),
+            public_key: *public_key.as_bytes(),
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    /// Checks that the key was signed by `identity_key` for the given peer id.
+    /// This only proves the key belongs to whoever holds the identity key, it's up to
+    /// the caller to decide whether that identity key is trusted.
+    pub fn verify(&self, id: &str) -> Result<()> {
+        verify_signature(
+            &self.identity_key,
+            &signed_data(id, &self.public_key),
+            &self.signature,
+        )
+        .map_err(|_| format!("Invalid signature on public key for {}", id).into())
+    }
+}
+
+/// Announces that a peer has replaced its X25519 key. The announcement is signed with
+/// the identity key and names the key being replaced, so an old announcement can't be
+/// replayed to roll a contact back to an earlier key.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct KeyChange {
+    pub previous_key: [u8; 32],
+    pub new_key: SignedPublicKey,
+    signature: Vec<u8>,
+}
+
+impl KeyChange {
+    pub fn sign(
+        id: &str,
+        previous_key: &PublicKey,
+        new_key: &PublicKey,
+        identity: &Keypair,
+    ) -> Self {
+        let new_key = SignedPublicKey::sign(id, new_key, identity);
+        let signature = identity.sign(&key_change_data(
+            id,
+            previous_key.as_bytes(),
+            &new_key.public_key,
+        ));
+        KeyChange {
+            previous_key: *previous_key.as_bytes(),
+            new_key,
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    /// Checks that the change was signed by the identity key of the new key. The caller
+    /// still has to check that it's the identity key and the previous key it knows for `id`.
+    pub fn verify(&self, id: &str) -> Result<()> {
+        self.new_key.verify(id)?;
+        verify_signature(
+            &self.new_key.identity_key,
+            &key_change_data(id, &self.previous_key, &self.new_key.public_key),
+            &self.signature,
+        )
+        .map_err(|_| format!("Invalid signature on key change for {}", id).into())
+    }
+}
+
+/// Random nonce sent by the server during registration, the client proves it holds the
+/// identity key bound to its account by signing it.
+pub fn new_challenge() -> [u8; CHALLENGE_SIZE] {
+    let mut challenge = [0; CHALLENGE_SIZE];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+pub fn sign_challenge(id: &str, challenge: &[u8; CHALLENGE_SIZE], identity: &Keypair) -> Vec<u8> {
+    identity
+        .sign(&challenge_data(id, challenge))
+        .to_bytes()
+        .to_vec()
+}
+
+pub fn verify_challenge(
+    id: &str,
+    challenge: &[u8; CHALLENGE_SIZE],
+    identity_key: &[u8; IDENTITY_KEY_SIZE],
+    signature: &[u8],
+) -> Result<()> {
+    verify_signature(identity_key, &challenge_data(id, challenge), signature)
+}
+
+pub(crate) fn verify_signature(
+    identity_key: &[u8; IDENTITY_KEY_SIZE],
+    data: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    let identity_key =
+        IdentityPublicKey::from_bytes(identity_key).map_err(|_| "Invalid identity key")?;
+    let signature = Signature::try_from(signature).map_err(|_| "Malformed signature")?;
+    identity_key
+        .verify_strict(data, &signature)
+        .map_err(|_| "Invalid signature".into())
+}
+
+fn challenge_data(id: &str, challenge: &[u8; CHALLENGE_SIZE]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(CHALLENGE_LABEL.len() + 4 + id.len() + CHALLENGE_SIZE);
+    data.extend_from_slice(CHALLENGE_LABEL);
+    data.extend_from_slice(&(id.len() as u32).to_be_bytes());
+    data.extend_from_slice(id.as_bytes());
+    data.extend_from_slice(challenge);
+    data
+}
+
+fn key_change_data(id: &str, previous_key: &[u8; 32], new_key: &[u8; 32]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(KEY_CHANGE_LABEL.len() + 4 + id.len() + 64);
+    data.extend_from_slice(KEY_CHANGE_LABEL);
+    data.extend_from_slice(&(id.len() as u32).to_be_bytes());
+    data.extend_from_slice(id.as_bytes());
+    data.extend_from_slice(previous_key);
+    data.extend_from_slice(new_key);
+    data
+}
+
+fn signed_data(id: &str, public_key: &[u8; 32]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(SIGNED_KEY_LABEL.len() + 4 + id.len() + 32);
+    data.extend_from_slice(SIGNED_KEY_LABEL);
+    data.extend_from_slice(&(id.len() as u32).to_be_bytes());
+    data.extend_from_slice(id.as_bytes());
+    data.extend_from_slice(public_key);
+    data
+}
@@ -0,0 +1,62 @@
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, SharedSecret};
+
+pub const KEY_SIZE: usize = 32;
+const SESSION_KEYS_LABEL: &[u8] = b"encrypter session keys v1";
+
+/// Symmetric keys for a conversation between two peers. Each direction has its own key
+/// so the two parties never encrypt with the same key, the root key seeds the ratchet.
+pub struct SessionKeys {
+    pub root: [u8; KEY_SIZE],
+    pub send: [u8; KEY_SIZE],
+    pub receive: [u8; KEY_SIZE],
+}
+
+impl SessionKeys {
+    /// Runs the Diffie-Hellman output through HKDF-SHA256 together with a context label
+    /// and both public keys. The keys are ordered so both peers derive the same output,
+    /// after the root key comes the key used by the peer with the lower public key for
+    /// sending and then the key used by the peer with the higher public key.
+    pub fn derive(
+        shared_secret: &SharedSecret,
+        own_public: &PublicKey,
+        peer_public: &PublicKey,
+    ) -> Self {
+        let own_is_lower = own_public.as_bytes() <= peer_public.as_bytes();
+        let (lower, higher) = if own_is_lower {
+            (own_public, peer_public)
+        } else {
+            (peer_public, own_public)
+        };
+        let mut info = Vec::with_capacity(SESSION_KEYS_LABEL.len() + 2 * KEY_SIZE);
+        info.extend_from_slice(SESSION_KEYS_LABEL);
+        info.extend_from_slice(lower.as_bytes());
+        info.extend_from_slice(higher.as_bytes());
+
+        let hkdf = Hkdf::<Sha256>::new(None, shared_secret.as_bytes());
+        let mut output = [0; 3 * KEY_SIZE];
+        hkdf.expand(&info, &mut output)
+            .expect("Output size is valid for HKDF-SHA256");
+
+        let mut root = [0; KEY_SIZE];
+        let mut lower_to_higher = [0; KEY_SIZE];
+        let mut higher_to_lower = [0; KEY_SIZE];
+        root.copy_from_slice(&output[..KEY_SIZE]);
+        lower_to_higher.copy_from_slice(&output[KEY_SIZE..2 * KEY_SIZE]);
+        higher_to_lower.copy_from_slice(&output[2 * KEY_SIZE..]);
+        if own_is_lower {
+            SessionKeys {
+                root,
+                send: lower_to_higher,
+                receive: higher_to_lower,
+            }
+        } else {
+            SessionKeys {
+                root,
+                send: higher_to_lower,
+                receive: lower_to_higher,
+            }
+        }
+    }
+}
@@ -1,64 +1,297 @@
-use aes_soft::block_cipher_trait::generic_array::GenericArray;
-use aes_soft::block_cipher_trait::BlockCipher;
-use aes_soft::Aes256;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use error::{MessageReference, ServerError};
+use group::{GroupMessage, GroupUpdate};
+use handshake::{ClientHello, ServerHello};
+use identity::{KeyChange, SignedPublicKey, CHALLENGE_SIZE};
+use keys::KEY_SIZE;
+use padding::PaddingPolicy;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use ratchet::RatchetHeader;
+use sealed::SealedEnvelope;
 use serde::{Deserialize, Serialize};
-use x25519_dalek::SharedSecret;
+use std::time::{SystemTime, UNIX_EPOCH};
+use x3dh::{InitialHeader, OneTimePrekey, PrekeyBundle, SignedPrekey};
+
+pub mod error;
+pub mod fingerprint;
+pub mod framing;
+pub mod group;
+pub mod handshake;
+pub mod identity;
+pub mod keys;
+pub mod padding;
+pub mod ratchet;
+pub mod sealed;
+pub mod sequence;
+pub mod x3dh;
 
 pub const ID_MAX_SIZE: usize = 32 - std::mem::size_of::<String>();
 pub const MESSAGE_MAX_SIZE: usize = 256 - std::mem::size_of::<String>();
-pub const MESSAGE_PACKET_SIZE: usize = 32 + 32 + 256;
+pub const NONCE_SIZE: usize = 12;
+pub const MESSAGE_ID_SIZE: usize = 16;
+const SEQUENCE_SIZE: usize = 8;
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
-pub struct EncryptedMessage(Message);
+
+/// Ids are shown in the chat list and used for routing so they have to be non empty,
+/// short and free of whitespace and control characters
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= ID_MAX_SIZE
+        && !id.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// A message where the content is encrypted with ChaCha20-Poly1305 using a message key
+/// from a `ratchet::Session`. The sender and receiver ids are stored next to the ratchet
+/// header, they are all authenticated as associated data so they can't be swapped without
+/// decryption failing. It's sent inside a `SealedEnvelope` so the server doesn't see them.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct EncryptedMessage {
+    from: String,
+    to: String,
+    header: RatchetHeader,
+    /// Set on the messages of a conversation started from a prekey bundle,
+    /// boxed since it's large and rarely present
+    initial: Option<Box<InitialHeader>>,
+    nonce: [u8; NONCE_SIZE],
+    ciphertext: Vec<u8>,
+}
 
 impl EncryptedMessage {
-    pub fn create(mut message: Message, shared_key: &SharedSecret) -> Self {
-        let key = GenericArray::from_slice(shared_key.as_bytes());
-        let cipher = Aes256::new(&key);
-
-        // Padd the message to be a multiple of 16
-        let padd_size = 16 - (message.content.len() % 16);
-        message.content.resize(message.content.len() + padd_size, 0);
-        message
-            .content
-            .as_mut_slice()
-            .chunks_exact_mut(16)
-            .for_each(|mut chunk| {
-                cipher.encrypt_block(GenericArray::from_mut_slice(&mut chunk));
-            });
-
-        EncryptedMessage(message)
+    pub(crate) fn create(
+        message: Message,
+        header: RatchetHeader,
+        initial: Option<InitialHeader>,
+        key: &[u8; KEY_SIZE],
+        padding: &PaddingPolicy,
+    ) -> Result<Self> {
+        let cipher = ChaCha20Poly1305::new(&Key::from(*key));
+        // A fresh random nonce for every message, 96 bits is large enough
+        // for random nonces to be safe with the message volumes of a chat
+        let mut nonce = [0; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+
+        // The sequence number goes in front of the body so it's authenticated and hidden
+        let mut plaintext = message.sequence.to_be_bytes().to_vec();
+        plaintext.extend_from_slice(&message.body.encode()?);
+        padding.pad(&mut plaintext);
+
+        let aad = associated_data(&message.from, &message.to, &header, initial.as_ref())?;
+        let ciphertext = cipher
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| "Failed to encrypt message")?;
+
+        Ok(EncryptedMessage {
+            from: message.from,
+            to: message.to,
+            header,
+            initial: initial.map(Box::new),
+            nonce,
+            ciphertext,
+        })
     }
-    pub fn decrypt_message(mut self, shared_key: &SharedSecret) -> Message {
-        let key = GenericArray::from_slice(shared_key.as_bytes());
-        let cipher = Aes256::new(&key);
-        self.0
-            .content
-            .as_mut_slice()
-            .chunks_exact_mut(16)
-            .for_each(|mut chunk| {
-                cipher.decrypt_block(GenericArray::from_mut_slice(&mut chunk));
-            });
-        self.0
+
+    pub(crate) fn decrypt_message(self, key: &[u8; KEY_SIZE]) -> Result<Message> {
+        let cipher = ChaCha20Poly1305::new(&Key::from(*key));
+        let aad = associated_data(&self.from, &self.to, &self.header, self.initial.as_deref())?;
+        let mut plaintext = cipher
+            .decrypt(
+                &Nonce::from(self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| "Message authentication failed")?;
+        // The padding is authenticated so an error here means the sender is misbehaving
+        padding::unpad(&mut plaintext)?;
+        if plaintext.len() < SEQUENCE_SIZE {
+            return Err("Message is missing its sequence number".into());
+        }
+        let mut sequence = [0; SEQUENCE_SIZE];
+        sequence.copy_from_slice(&plaintext[..SEQUENCE_SIZE]);
+        Ok(Message {
+            from: self.from,
+            to: self.to,
+            sequence: u64::from_be_bytes(sequence),
+            body: MessageBody::decode(&plaintext[SEQUENCE_SIZE..])?,
+        })
     }
 
     pub fn get_info(&self) -> (&String, &String) {
-        (&self.0.from, &self.0.to)
+        (&self.from, &self.to)
     }
+
+    pub fn header(&self) -> &RatchetHeader {
+        &self.header
+    }
+
+    pub fn initial_header(&self) -> Option<&InitialHeader> {
+        self.initial.as_deref()
+    }
+}
+
+// Length prefix the ids so ("ab", "c") and ("a", "bc") don't produce the same associated data
+fn associated_data(
+    from: &str,
+    to: &str,
+    header: &RatchetHeader,
+    initial: Option<&InitialHeader>,
+) -> Result<Vec<u8>> {
+    let mut aad = Vec::with_capacity(8 + from.len() + to.len());
+    aad.extend_from_slice(&(from.len() as u32).to_be_bytes());
+    aad.extend_from_slice(from.as_bytes());
+    aad.extend_from_slice(&(to.len() as u32).to_be_bytes());
+    aad.extend_from_slice(to.as_bytes());
+    aad.extend_from_slice(&bincode::serialize(header)?);
+    aad.extend_from_slice(&bincode::serialize(&initial)?);
+    Ok(aad)
 }
+
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct Message {
     pub from: String,
     pub to: String,
+    /// Number of messages sent before this one in the session, set by `Session::encrypt`
+    pub sequence: u64,
+    pub body: MessageBody,
+}
+
+impl Message {
+    pub fn new(from: String, to: String, body: MessageBody) -> Self {
+        Message {
+            from,
+            to,
+            sequence: 0,
+            body,
+        }
+    }
+}
+
+/// Random id chosen by the sender of a message
+pub type MessageId = [u8; MESSAGE_ID_SIZE];
+
+/// Tells the receiver how to read the content of a `MessageBody`
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ContentType {
+    /// UTF-8 text
+    Text,
+    /// A bincode encoded `GroupUpdate`
+    GroupUpdate,
+}
+
+/// The encrypted part of a pairwise or group message, the metadata is encrypted
+/// together with the content so only the recipients can see or change it.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct MessageBody {
+    pub id: MessageId,
+    /// Milliseconds since the Unix epoch on the sender's clock, which can't be trusted
+    /// for anything but showing the user when the message was written
+    pub timestamp: u64,
+    pub content_type: ContentType,
     pub content: Vec<u8>,
 }
+
+impl MessageBody {
+    /// Body with a new random id and the current time
+    pub fn new(content: &MessageKind) -> Result<Self> {
+        let mut id = [0; MESSAGE_ID_SIZE];
+        OsRng.fill_bytes(&mut id);
+        let (content_type, content) = match content {
+            MessageKind::Text(text) => (ContentType::Text, text.as_bytes().to_vec()),
+            MessageKind::GroupUpdate(update) => {
+                (ContentType::GroupUpdate, bincode::serialize(update)?)
+            }
+        };
+        Ok(MessageBody {
+            id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|_| "The system clock is set before 1970")?
+                .as_millis() as u64,
+            content_type,
+            content,
+        })
+    }
+
+    /// Reads the content according to the content type
+    pub fn kind(&self) -> Result<MessageKind> {
+        Ok(match self.content_type {
+            ContentType::Text => MessageKind::Text(String::from_utf8(self.content.clone())?),
+            ContentType::GroupUpdate => {
+                MessageKind::GroupUpdate(bincode::deserialize(&self.content)?)
+            }
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn decode(body: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(body)?)
+    }
+}
+
+/// The content of a `MessageBody` once decoded
+#[derive(Debug, PartialEq, Clone)]
+pub enum MessageKind {
+    Text(String),
+    /// Invites the receiver to a group or hands it the sender's key for a group
+    GroupUpdate(GroupUpdate),
+}
+
+/// Messages sent from a client to the server.
+/// bincode encodes enum variants by their index so the handshake variant must stay
+/// first and never change, that way peers running any version can always parse it
+/// and reject each other cleanly.
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub enum ClientMessage {
+    Hello(ClientHello),
+    NewConnection(String, SignedPublicKey),
+    /// Signature over the registration challenge made with the identity key
+    ChallengeResponse(Vec<u8>),
+    /// Replaces the signed prekey and the one-time prekeys stored on the server
+    UploadPrekeys(SignedPrekey, Vec<OneTimePrekey>),
+    FetchPrekeyBundle(String),
+    /// Asks for the key bound to an account, used to confirm the sender of a message
+    /// starting a session since the server doesn't check who sent a sealed message
+    FetchPublicKey(String),
+    /// Replaces the X25519 key of the connection, forwarded to every peer including
+    /// the sender, which only switches to the new key once it gets it back
+    RotateKey(MessageReference, KeyChange),
+    Message(MessageReference, SealedEnvelope),
+    /// The server delivers the same ciphertext to every listed member of the group
+    GroupMessage(MessageReference, Vec<String>, GroupMessage),
+    Disconnect(String),
+}
+
+/// Messages sent from the server to a client, the handshake replies must stay
+/// first for the same reason as in `ClientMessage`.
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
-pub enum Protocol {
-    Message(EncryptedMessage),
-    NewConnection(String, [u8; 32]),
-    InternalRemoveConnection,
-    ConnectionLost,
+pub enum ServerMessage {
+    Welcome(ServerHello),
+    Error(ServerError),
+    /// Sent in response to `NewConnection`, the registration completes once the
+    /// client has signed it with its identity key
+    Challenge([u8; CHALLENGE_SIZE]),
+    NewConnection(String, SignedPublicKey),
+    /// The peers that are online, sent after the registration completes. Large lists are
+    /// split over several messages to stay under the max frame size, the flag is set on
+    /// the last one.
+    PeerList(Vec<(String, SignedPublicKey)>, bool),
+    PrekeyBundle(String, Box<PrekeyBundle>),
+    PublicKey(String, SignedPublicKey),
+    KeyChange(String, KeyChange),
+    Message(SealedEnvelope),
+    GroupMessage(GroupMessage),
     Disconnect(String),
-    PeerList(Vec<(String, [u8; 32])>),
 }
@@ -0,0 +1,178 @@
+use std::str::FromStr;
+
+/// Size of the length field at the start of padded content
+pub const LENGTH_SIZE: usize = 4;
+/// Buckets used by `PaddingPolicy::default`, a text message always fits in the smallest one
+pub const DEFAULT_BUCKETS: &[usize] = &[512, 1024, 4096];
+
+/// Content is padded to one of a few fixed sizes before encryption so the ciphertext
+/// only reveals which bucket a message falls in, not its length. Content larger than
+/// the largest bucket is padded to a multiple of it.
+/// Only the sender needs the policy, padded content can be removed with `unpad`
+/// whatever buckets it was padded to.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PaddingPolicy {
+    buckets: Vec<usize>,
+}
+
+impl PaddingPolicy {
+    /// Fails unless the buckets are in ascending order and each one is larger
+    /// than the length field
+    pub fn new(buckets: Vec<usize>) -> crate::Result<Self> {
+        if buckets.is_empty() {
+            return Err("A padding policy needs at least one bucket".into());
+        }
+        if buckets[0] <= LENGTH_SIZE {
+            return Err(
+                format!("Padding buckets must be larger than {} bytes", LENGTH_SIZE).into(),
+            );
+        }
+        if buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("Padding buckets must be in ascending order".into());
+        }
+        Ok(PaddingPolicy { buckets })
+    }
+
+    pub fn buckets(&self) -> &[usize] {
+        &self.buckets
+    }
+
+    /// Size of the padded content for `content_size` bytes of content
+    pub fn padded_size(&self, content_size: usize) -> usize {
+        let size = content_size + LENGTH_SIZE;
+        match self.buckets.iter().find(|&&bucket| bucket >= size) {
+            Some(&bucket) => bucket,
+            None => {
+                let largest = self.buckets[self.buckets.len() - 1];
+                size.div_ceil(largest) * largest
+            }
+        }
+    }
+
+    /// Prefixes the content with its length as a big endian u32 and fills the rest
+    /// of the bucket with zeros
+    pub fn pad(&self, content: &mut Vec<u8>) {
+        let padded_size = self.padded_size(content.len());
+        let length = (content.len() as u32).to_be_bytes();
+        content.splice(0..0, length.iter().copied());
+        content.resize(padded_size, 0);
+    }
+}
+
+impl Default for PaddingPolicy {
+    fn default() -> Self {
+        PaddingPolicy {
+            buckets: DEFAULT_BUCKETS.to_vec(),
+        }
+    }
+}
+
+/// Parses a comma separated list of bucket sizes in bytes, like "512,1024,4096"
+impl FromStr for PaddingPolicy {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(buckets: &str) -> crate::Result<Self> {
+        let buckets = buckets
+            .split(',')
+            .map(|bucket| bucket.trim().parse())
+            .collect::<std::result::Result<Vec<usize>, _>>()
+            .map_err(|err| format!("Invalid padding bucket: {}", err))?;
+        PaddingPolicy::new(buckets)
+    }
+}
+
+/// Removes padding added by `PaddingPolicy::pad`, fails if the length field
+/// doesn't fit the content or the padding isn't all zeros.
+pub fn unpad(content: &mut Vec<u8>) -> crate::Result<()> {
+    if content.len() < LENGTH_SIZE {
+        return Err("Padded content has invalid length".into());
+    }
+    let mut length = [0; LENGTH_SIZE];
+    length.copy_from_slice(&content[..LENGTH_SIZE]);
+    let content_size = u32::from_be_bytes(length) as usize;
+    if content_size > content.len() - LENGTH_SIZE {
+        return Err("Invalid padding size".into());
+    }
+    if content[LENGTH_SIZE + content_size..]
+        .iter()
+        .any(|&byte| byte != 0)
+    {
+        return Err("Invalid padding".into());
+    }
+    content.truncate(LENGTH_SIZE + content_size);
+    content.drain(..LENGTH_SIZE);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the padded size
+    fn round_trip(policy: &PaddingPolicy, size: usize) -> usize {
+        let content: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let mut padded = content.clone();
+        policy.pad(&mut padded);
+        let padded_size = padded.len();
+        unpad(&mut padded).unwrap();
+        assert_eq!(padded, content);
+        padded_size
+    }
+
+    #[test]
+    fn round_trips_any_length() {
+        let policy = PaddingPolicy::default();
+        let largest = DEFAULT_BUCKETS[DEFAULT_BUCKETS.len() - 1];
+        for &size in &[
+            0,
+            1,
+            15,
+            16,
+            17,
+            512 - LENGTH_SIZE,
+            largest,
+            3 * largest + 1,
+        ] {
+            assert!(round_trip(&policy, size) >= size + LENGTH_SIZE);
+        }
+    }
+
+    #[test]
+    fn pads_to_buckets() {
+        let policy = PaddingPolicy::default();
+        assert_eq!(round_trip(&policy, 0), 512);
+        assert_eq!(round_trip(&policy, 1), 512);
+        assert_eq!(round_trip(&policy, 512 - LENGTH_SIZE), 512);
+        assert_eq!(round_trip(&policy, 512 - LENGTH_SIZE + 1), 1024);
+        assert_eq!(round_trip(&policy, 4096 - LENGTH_SIZE), 4096);
+    }
+
+    #[test]
+    fn pads_to_multiple_of_largest_bucket() {
+        let policy = PaddingPolicy::default();
+        assert_eq!(round_trip(&policy, 4096 - LENGTH_SIZE + 1), 8192);
+        assert_eq!(round_trip(&policy, 10_000), 12_288);
+    }
+
+    #[test]
+    fn rejects_invalid_padding() {
+        // Shorter than the length field
+        assert!(unpad(&mut vec![0, 0, 1]).is_err());
+        // Length larger than the content
+        assert!(unpad(&mut vec![0, 0, 0, 2, 1]).is_err());
+        // Padding that isn't zeros
+        assert!(unpad(&mut vec![0, 0, 0, 1, 1, 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        assert!(PaddingPolicy::new(Vec::new()).is_err());
+        assert!(PaddingPolicy::new(vec![LENGTH_SIZE]).is_err());
+        assert!(PaddingPolicy::new(vec![1024, 512]).is_err());
+        assert!("512,x".parse::<PaddingPolicy>().is_err());
+        assert_eq!(
+            " 64, 128 ".parse::<PaddingPolicy>().unwrap().buckets(),
+            &[64, 128]
+        );
+    }
+}
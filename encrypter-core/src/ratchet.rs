@@ -0,0 +1,486 @@
+use crate::keys::{SessionKeys, KEY_SIZE};
+use crate::padding::PaddingPolicy;
+use crate::sequence::{SequenceStatus, SequenceTracker};
+use crate::x3dh::InitialHeader;
+use crate::{EncryptedMessage, Message, Result};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Max number of message keys that will be derived and stored when skipping ahead
+/// in a single receiving chain. Protects against a malicious header forcing the
+/// receiver to compute an unbounded amount of keys.
+pub const MAX_SKIP: u32 = 1000;
+/// Max number of skipped message keys kept around in total, the oldest are dropped first.
+const MAX_SKIPPED_KEYS: usize = 2 * MAX_SKIP as usize;
+const ROOT_KDF_LABEL: &[u8] = b"encrypter ratchet v1";
+
+pub(crate) type ChainKey = [u8; KEY_SIZE];
+pub(crate) type MessageKey = [u8; KEY_SIZE];
+
+/// Sent in plaintext (but authenticated) together with every message so the
+/// receiver knows which chain and message key to use.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub struct RatchetHeader {
+    /// The sender's current ratchet public key
+    pub dh: [u8; 32],
+    /// Number of messages sent in the sender's previous sending chain
+    pub previous_chain_length: u32,
+    /// Number of the message in the current sending chain
+    pub message_number: u32,
+}
+
+/// A Double Ratchet session with a single peer. Every message is encrypted with a
+/// new message key from a symmetric chain and the chains are replaced by a new
+/// Diffie-Hellman exchange every time the conversation changes direction,
+/// which gives both forward secrecy and recovery after a key compromise.
+/// Serializing a session includes its secret keys, the output must be stored encrypted.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "SessionState", from = "SessionState")]
+pub struct Session {
+    root_key: [u8; KEY_SIZE],
+    dh_self: StaticSecret,
+    // Unknown to the responder of an X3DH session until the first message arrives
+    dh_remote: Option<PublicKey>,
+    sending_chain: Option<ChainKey>,
+    receiving_chain: Option<ChainKey>,
+    sent_count: u32,
+    received_count: u32,
+    previous_sending_count: u32,
+    skipped_keys: VecDeque<(([u8; 32], u32), MessageKey)>,
+    // Sent along with every message until the peer has replied
+    initial_header: Option<InitialHeader>,
+    // Number of messages sent in the session, the next message carries it
+    sent_sequence: u64,
+    received_sequences: SequenceTracker,
+}
+
+impl Session {
+    /// Bootstraps a session from the static keys of both peers. The initial chains
+    /// come from the static key agreement so both sides can send right away, the peer
+    /// with the lower public key then immediately performs a DH ratchet step so the
+    /// first message it sends starts the ratchet for the conversation.
+    pub fn new(own_secret: &StaticSecret, peer_public: PublicKey) -> Self {
+        let own_public = PublicKey::from(own_secret);
+        let session_keys = SessionKeys::derive(
+            &own_secret.diffie_hellman(&peer_public),
+            &own_public,
+            &peer_public,
+        );
+        let mut session = Session {
+            root_key: session_keys.root,
+            dh_self: own_secret.clone(),
+            dh_remote: Some(peer_public),
+            sending_chain: Some(session_keys.send),
+            receiving_chain: Some(session_keys.receive),
+            sent_count: 0,
+            received_count: 0,
+            previous_sending_count: 0,
+            skipped_keys: VecDeque::new(),
+            initial_header: None,
+            sent_sequence: 0,
+            received_sequences: SequenceTracker::new(),
+        };
+        if own_public.as_bytes() < peer_public.as_bytes() {
+            session.sending_ratchet_step();
+        }
+        session
+    }
+
+    /// Session for the party that started an X3DH key agreement, the first sending
+    /// chain comes from a DH ratchet step against the peer's signed prekey.
+    pub fn initiate(
+        shared_secret: [u8; KEY_SIZE],
+        remote_prekey: PublicKey,
+        initial_header: InitialHeader,
+    ) -> Self {
+        let mut session = Session {
+            root_key: shared_secret,
+            dh_self: StaticSecret::new(&mut OsRng),
+            dh_remote: Some(remote_prekey),
+            sending_chain: None,
+            receiving_chain: None,
+            sent_count: 0,
+            received_count: 0,
+            previous_sending_count: 0,
+            skipped_keys: VecDeque::new(),
+            initial_header: Some(initial_header),
+            sent_sequence: 0,
+            received_sequences: SequenceTracker::new(),
+        };
+        session.sending_ratchet_step();
+        session
+    }
+
+    /// Session for the receiving side of an X3DH key agreement, it can't send
+    /// anything until the first message from the initiator has been decrypted.
+    pub fn respond(shared_secret: [u8; KEY_SIZE], signed_prekey: StaticSecret) -> Self {
+        Session {
+            root_key: shared_secret,
+            dh_self: signed_prekey,
+            dh_remote: None,
+            sending_chain: None,
+            receiving_chain: None,
+            sent_count: 0,
+            received_count: 0,
+            previous_sending_count: 0,
+            skipped_keys: VecDeque::new(),
+            initial_header: None,
+            sent_sequence: 0,
+            received_sequences: SequenceTracker::new(),
+        }
+    }
+
+    /// The header of the X3DH exchange that started this session, if the peer
+    /// hasn't replied yet
+    pub fn initial_header(&self) -> Option<&InitialHeader> {
+        self.initial_header.as_ref()
+    }
+
+    /// Whether any message from the peer has been decrypted with this session
+    pub fn has_received(&self) -> bool {
+        !self.received_sequences.is_empty()
+    }
+
+    /// Encrypts the message with the next key of the sending chain, the content
+    /// is padded to one of the buckets of `padding` first. The sequence number
+    /// of the message is replaced with the next one of the session.
+    pub fn encrypt(
+        &mut self,
+        mut message: Message,
+        padding: &PaddingPolicy,
+    ) -> Result<EncryptedMessage> {
+        let sending_chain = self
+            .sending_chain
+            .ok_or("Can't send before the first message from the peer has arrived")?;
+        let (chain_key, message_key) = kdf_chain(&sending_chain);
+        let header = RatchetHeader {
+            dh: *PublicKey::from(&self.dh_self).as_bytes(),
+            previous_chain_length: self.previous_sending_count,
+            message_number: self.sent_count,
+        };
+        message.sequence = self.sent_sequence;
+        let encrypted = EncryptedMessage::create(
+            message,
+            header,
+            self.initial_header.clone(),
+            &message_key,
+            padding,
+        )?;
+        self.sending_chain = Some(chain_key);
+        self.sent_count += 1;
+        self.sent_sequence += 1;
+        Ok(encrypted)
+    }
+
+    /// Decrypts the message and advances the ratchet. The session is left untouched
+    /// if decryption fails, so forged messages can't corrupt the state.
+    /// Messages whose sequence number has been seen before are rejected, the status
+    /// tells if any messages before this one are missing or if it arrived late.
+    pub fn decrypt(&mut self, message: EncryptedMessage) -> Result<(Message, SequenceStatus)> {
+        let mut next_state = self.clone();
+        let decrypted = next_state.ratchet_decrypt(message)?;
+        let status = next_state.received_sequences.check(decrypted.sequence)?;
+        if status == SequenceStatus::Duplicate {
+            return Err(format!("Duplicate message {}", decrypted.sequence).into());
+        }
+        // Anything decrypted means the peer has the session, no need to keep
+        // sending the initial header
+        next_state.initial_header = None;
+        *self = next_state;
+        Ok((decrypted, status))
+    }
+
+    fn ratchet_decrypt(&mut self, message: EncryptedMessage) -> Result<Message> {
+        let header = *message.header();
+        if let Some(message_key) = self.take_skipped_key(&header) {
+            return message.decrypt_message(&message_key);
+        }
+        if self.dh_remote.map(|key| *key.as_bytes()) != Some(header.dh) {
+            self.skip_message_keys(header.previous_chain_length)?;
+            self.dh_ratchet(&header);
+        }
+        self.skip_message_keys(header.message_number)?;
+        let receiving_chain = self.receiving_chain.ok_or("No receiving chain")?;
+        let (chain_key, message_key) = kdf_chain(&receiving_chain);
+        self.receiving_chain = Some(chain_key);
+        self.received_count += 1;
+        message.decrypt_message(&message_key)
+    }
+
+    fn take_skipped_key(&mut self, header: &RatchetHeader) -> Option<MessageKey> {
+        let position = self
+            .skipped_keys
+            .iter()
+            .position(|(index, _)| *index == (header.dh, header.message_number))?;
+        self.skipped_keys
+            .remove(position)
+            .map(|(_, message_key)| message_key)
+    }
+
+    // Store the keys of messages that haven't arrived yet in the current receiving
+    // chain so they can still be decrypted when they arrive out of order.
+    fn skip_message_keys(&mut self, until: u32) -> Result<()> {
+        let (dh_remote, mut receiving_chain) = match (self.dh_remote, self.receiving_chain) {
+            (Some(dh_remote), Some(receiving_chain)) => (dh_remote, receiving_chain),
+            // Nothing has been received in the current chain yet
+            _ => return Ok(()),
+        };
+        if until < self.received_count {
+            // Already past this point in the chain, either a replay or a message
+            // whose key has been used already.
+            return Ok(());
+        }
+        if until - self.received_count > MAX_SKIP {
+            return Err("Too many skipped messages".into());
+        }
+        while self.received_count < until {
+            let (chain_key, message_key) = kdf_chain(&receiving_chain);
+            receiving_chain = chain_key;
+            self.skipped_keys
+                .push_back(((*dh_remote.as_bytes(), self.received_count), message_key));
+            if self.skipped_keys.len() > MAX_SKIPPED_KEYS {
+                self.skipped_keys.pop_front();
+            }
+            self.received_count += 1;
+        }
+        self.receiving_chain = Some(receiving_chain);
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, header: &RatchetHeader) {
+        let dh_remote = PublicKey::from(header.dh);
+        self.dh_remote = Some(dh_remote);
+        self.received_count = 0;
+        let (root_key, receiving_chain) = kdf_root(
+            &self.root_key,
+            self.dh_self.diffie_hellman(&dh_remote).as_bytes(),
+        );
+        self.root_key = root_key;
+        self.receiving_chain = Some(receiving_chain);
+        self.sending_ratchet_step();
+    }
+
+    // Only called once the remote ratchet key is known
+    fn sending_ratchet_step(&mut self) {
+        let dh_remote = self.dh_remote.expect("Remote ratchet key to be known");
+        self.previous_sending_count = self.sent_count;
+        self.sent_count = 0;
+        self.dh_self = StaticSecret::new(&mut OsRng);
+        let (root_key, sending_chain) = kdf_root(
+            &self.root_key,
+            self.dh_self.diffie_hellman(&dh_remote).as_bytes(),
+        );
+        self.root_key = root_key;
+        self.sending_chain = Some(sending_chain);
+    }
+}
+
+// Serializable form of a session, the dalek key types don't implement serde
+#[derive(Serialize, Deserialize)]
+struct SessionState {
+    root_key: [u8; KEY_SIZE],
+    dh_self: [u8; 32],
+    dh_remote: Option<[u8; 32]>,
+    sending_chain: Option<ChainKey>,
+    receiving_chain: Option<ChainKey>,
+    sent_count: u32,
+    received_count: u32,
+    previous_sending_count: u32,
+    skipped_keys: VecDeque<(([u8; 32], u32), MessageKey)>,
+    initial_header: Option<InitialHeader>,
+    sent_sequence: u64,
+    received_sequences: SequenceTracker,
+}
+
+impl From<Session> for SessionState {
+    fn from(session: Session) -> Self {
+        SessionState {
+            root_key: session.root_key,
+            dh_self: session.dh_self.to_bytes(),
+            dh_remote: session.dh_remote.map(|key| *key.as_bytes()),
+            sending_chain: session.sending_chain,
+            receiving_chain: session.receiving_chain,
+            sent_count: session.sent_count,
+            received_count: session.received_count,
+            previous_sending_count: session.previous_sending_count,
+            skipped_keys: session.skipped_keys,
+            initial_header: session.initial_header,
+            sent_sequence: session.sent_sequence,
+            received_sequences: session.received_sequences,
+        }
+    }
+}
+
+impl From<SessionState> for Session {
+    fn from(state: SessionState) -> Self {
+        Session {
+            root_key: state.root_key,
+            dh_self: StaticSecret::from(state.dh_self),
+            dh_remote: state.dh_remote.map(PublicKey::from),
+            sending_chain: state.sending_chain,
+            receiving_chain: state.receiving_chain,
+            sent_count: state.sent_count,
+            received_count: state.received_count,
+            previous_sending_count: state.previous_sending_count,
+            skipped_keys: state.skipped_keys,
+            initial_header: state.initial_header,
+            sent_sequence: state.sent_sequence,
+            received_sequences: state.received_sequences,
+        }
+    }
+}
+
+fn kdf_root(root_key: &[u8; KEY_SIZE], dh_output: &[u8; 32]) -> ([u8; KEY_SIZE], ChainKey) {
+    let hkdf = Hkdf::<Sha256>::new(Some(root_key), dh_output);
+    let mut output = [0; 2 * KEY_SIZE];
+    hkdf.expand(ROOT_KDF_LABEL, &mut output)
+        .expect("Output size is valid for HKDF-SHA256");
+    let mut next_root_key = [0; KEY_SIZE];
+    let mut chain_key = [0; KEY_SIZE];
+    next_root_key.copy_from_slice(&output[..KEY_SIZE]);
+    chain_key.copy_from_slice(&output[KEY_SIZE..]);
+    (next_root_key, chain_key)
+}
+
+pub(crate) fn kdf_chain(chain_key: &ChainKey) -> (ChainKey, MessageKey) {
+    let hmac = |input: u8| {
+        let mut mac = Hmac::<Sha256>::new_varkey(chain_key).expect("HMAC accepts keys of any size");
+        mac.update(&[input]);
+        let mut output = [0; KEY_SIZE];
+        output.copy_from_slice(&mac.finalize().into_bytes());
+        output
+    };
+    (hmac(0x02), hmac(0x01))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageBody, MessageKind};
+
+    fn sessions() -> (Session, Session) {
+        let alice = StaticSecret::new(&mut OsRng);
+        let bob = StaticSecret::new(&mut OsRng);
+        (
+            Session::new(&alice, PublicKey::from(&bob)),
+            Session::new(&bob, PublicKey::from(&alice)),
+        )
+    }
+
+    fn encrypt(session: &mut Session, text: &str) -> EncryptedMessage {
+        let body = MessageBody::new(&MessageKind::Text(text.to_string())).unwrap();
+        let message = Message::new("alice".to_string(), "bob".to_string(), body);
+        session.encrypt(message, &PaddingPolicy::default()).unwrap()
+    }
+
+    fn decrypt(session: &mut Session, message: EncryptedMessage) -> String {
+        let (message, _) = session.decrypt(message).unwrap();
+        match message.body.kind().unwrap() {
+            MessageKind::Text(text) => text,
+            _ => panic!("Expected a text message"),
+        }
+    }
+
+    fn state(session: &Session) -> Vec<u8> {
+        bincode::serialize(session).unwrap()
+    }
+
+    #[test]
+    fn decrypts_skipped_and_out_of_order_messages() {
+        let (mut alice, mut bob) = sessions();
+        let mut sent: Vec<_> = (0..4)
+            .map(|i| Some(encrypt(&mut alice, &i.to_string())))
+            .collect();
+        for &i in &[3, 1, 0, 2] {
+            let message = sent[i].take().unwrap();
+            assert_eq!(decrypt(&mut bob, message), i.to_string());
+        }
+
+        // Replies move the ratchet forward, messages from the old chain still decrypt
+        let late = encrypt(&mut alice, "4");
+        let reply = encrypt(&mut bob, "reply");
+        assert_eq!(decrypt(&mut alice, reply), "reply");
+        let next = encrypt(&mut alice, "5");
+        assert_eq!(decrypt(&mut bob, next), "5");
+        assert_eq!(decrypt(&mut bob, late), "4");
+    }
+
+    #[test]
+    fn reports_sequence_of_received_messages() {
+        let (mut alice, mut bob) = sessions();
+        let sent: Vec<_> = (0..3)
+            .map(|i| encrypt(&mut alice, &i.to_string()))
+            .collect();
+        let statuses: Vec<_> = sent
+            .into_iter()
+            .rev()
+            .map(|message| bob.decrypt(message).unwrap().1)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                SequenceStatus::Gap(2),
+                SequenceStatus::OutOfOrder,
+                SequenceStatus::OutOfOrder
+            ]
+        );
+        let next = encrypt(&mut alice, "3");
+        assert_eq!(bob.decrypt(next).unwrap().1, SequenceStatus::InOrder);
+    }
+
+    #[test]
+    fn rejects_replayed_message() {
+        let (mut alice, mut bob) = sessions();
+        let message = encrypt(&mut alice, "hello");
+        let replay = bincode::deserialize(&bincode::serialize(&message).unwrap()).unwrap();
+        decrypt(&mut bob, message);
+        assert!(bob.decrypt(replay).is_err());
+    }
+
+    #[test]
+    fn rejects_too_many_skipped_messages() {
+        let (mut alice, mut bob) = sessions();
+        for _ in 0..=MAX_SKIP {
+            encrypt(&mut alice, "skipped");
+        }
+        let message = encrypt(&mut alice, "too far");
+        let before = state(&bob);
+        assert!(bob.decrypt(message).is_err());
+        assert_eq!(state(&bob), before);
+    }
+
+    #[test]
+    fn failed_decrypt_leaves_session_unchanged() {
+        let (mut alice, mut bob) = sessions();
+        encrypt(&mut alice, "skipped");
+        let message = encrypt(&mut alice, "hello");
+        let mut tampered: EncryptedMessage =
+            bincode::deserialize(&bincode::serialize(&message).unwrap()).unwrap();
+        tampered.ciphertext[0] ^= 1;
+
+        let before = state(&bob);
+        assert!(bob.decrypt(tampered).is_err());
+        assert_eq!(state(&bob), before);
+        assert_eq!(decrypt(&mut bob, message), "hello");
+    }
+
+    #[test]
+    fn session_survives_serialization() {
+        let (mut alice, bob) = sessions();
+        let skipped = encrypt(&mut alice, "skipped");
+        let message = encrypt(&mut alice, "hello");
+        let mut bob: Session = bincode::deserialize(&state(&bob)).unwrap();
+        decrypt(&mut bob, message);
+
+        let mut bob: Session = bincode::deserialize(&state(&bob)).unwrap();
+        assert_eq!(decrypt(&mut bob, skipped), "skipped");
+        let reply = encrypt(&mut bob, "reply");
+        let mut alice: Session = bincode::deserialize(&state(&alice)).unwrap();
+        assert_eq!(decrypt(&mut alice, reply), "reply");
+    }
+}
@@ -0,0 +1,214 @@
+use crate::keys::KEY_SIZE;
+use crate::padding::{self, PaddingPolicy};
+use crate::{EncryptedMessage, Result, NONCE_SIZE};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+const SEALED_SENDER_LABEL: &[u8] = b"encrypter sealed sender v1";
+
+/// Outer layer of a pairwise message. The whole `EncryptedMessage`, sender id included,
+/// is encrypted to the recipient's X25519 key with a key from an ephemeral Diffie-Hellman
+/// exchange, so the server only learns who the message is for. The sender is still
+/// authenticated by the ratchet session the inner message is encrypted with.
+/// The serialized message is padded before sealing, otherwise the size of the envelope
+/// would give away the length of the sender id and whether the message starts a session.
+/// Since the server can't check the sender, the receiver has to confirm the identity key
+/// of a sender it hasn't seen before with the server before trusting a new session.
+/// Group messages aren't sealed, the server sees their sender.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct SealedEnvelope {
+    to: String,
+    ephemeral_key: [u8; 32],
+    nonce: [u8; NONCE_SIZE],
+    ciphertext: Vec<u8>,
+}
+
+impl SealedEnvelope {
+    pub fn seal(
+        message: &EncryptedMessage,
+        recipient_key: &PublicKey,
+        padding: &PaddingPolicy,
+    ) -> Result<Self> {
+        let ephemeral = EphemeralSecret::new(&mut OsRng);
+        let ephemeral_key = PublicKey::from(&ephemeral);
+        let key = derive_key(
+            ephemeral.diffie_hellman(recipient_key).as_bytes(),
+            &ephemeral_key,
+            recipient_key,
+        );
+        let to = message.get_info().1.clone();
+        let mut nonce = [0; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut plaintext = bincode::serialize(message)?;
+        padding.pad(&mut plaintext);
+        let ciphertext = ChaCha20Poly1305::new(&Key::from(key))
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &associated_data(&to, ephemeral_key.as_bytes()),
+                },
+            )
+            .map_err(|_| "Failed to seal message")?;
+        Ok(SealedEnvelope {
+            to,
+            ephemeral_key: *ephemeral_key.as_bytes(),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Recovers the inner message, which still has to be decrypted with the sender's session
+    pub fn open(&self, own_secret: &StaticSecret) -> Result<EncryptedMessage> {
+        let ephemeral_key = PublicKey::from(self.ephemeral_key);
+        let key = derive_key(
+            own_secret.diffie_hellman(&ephemeral_key).as_bytes(),
+            &ephemeral_key,
+            &PublicKey::from(own_secret),
+        );
+        let mut plaintext = ChaCha20Poly1305::new(&Key::from(key))
+            .decrypt(
+                &Nonce::from(self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: &associated_data(&self.to, &self.ephemeral_key),
+                },
+            )
+            .map_err(|_| "Couldn't open sealed message")?;
+        padding::unpad(&mut plaintext)?;
+        let message: EncryptedMessage = bincode::deserialize(&plaintext)?;
+        // Otherwise a message for someone else could be forwarded in a new envelope
+        if message.get_info().1 != &self.to {
+            return Err("Sealed message was addressed to someone else".into());
+        }
+        Ok(message)
+    }
+
+    /// The only id the server can see, used for routing
+    pub fn to(&self) -> &str {
+        &self.to
+    }
+}
+
+fn derive_key(
+    dh_output: &[u8; 32],
+    ephemeral_key: &PublicKey,
+    recipient_key: &PublicKey,
+) -> [u8; KEY_SIZE] {
+    let mut info = Vec::with_capacity(SEALED_SENDER_LABEL.len() + 64);
+    info.extend_from_slice(SEALED_SENDER_LABEL);
+    info.extend_from_slice(ephemeral_key.as_bytes());
+    info.extend_from_slice(recipient_key.as_bytes());
+    let hkdf = Hkdf::<Sha256>::new(None, dh_output);
+    let mut key = [0; KEY_SIZE];
+    hkdf.expand(&info, &mut key)
+        .expect("Output size is valid for HKDF-SHA256");
+    key
+}
+
+fn associated_data(to: &str, ephemeral_key: &[u8; 32]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(4 + to.len() + 32);
+    aad.extend_from_slice(&(to.len() as u32).to_be_bytes());
+    aad.extend_from_slice(to.as_bytes());
+    aad.extend_from_slice(ephemeral_key);
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratchet::Session;
+    use crate::{Message, MessageBody, MessageKind};
+
+    fn message_to(to: &str) -> EncryptedMessage {
+        let alice = StaticSecret::new(&mut OsRng);
+        let recipient = StaticSecret::new(&mut OsRng);
+        let body = MessageBody::new(&MessageKind::Text("hello".to_string())).unwrap();
+        let message = Message::new("alice".to_string(), to.to_string(), body);
+        Session::new(&alice, PublicKey::from(&recipient))
+            .encrypt(message, &PaddingPolicy::default())
+            .unwrap()
+    }
+
+    // What a server forwarding someone else's message would have to do, the inner
+    // message can't be changed without the sender's session
+    fn reseal(message: &EncryptedMessage, to: &str, recipient_key: &PublicKey) -> SealedEnvelope {
+        let ephemeral = EphemeralSecret::new(&mut OsRng);
+        let ephemeral_key = PublicKey::from(&ephemeral);
+        let key = derive_key(
+            ephemeral.diffie_hellman(recipient_key).as_bytes(),
+            &ephemeral_key,
+            recipient_key,
+        );
+        let nonce = [0; NONCE_SIZE];
+        let mut plaintext = bincode::serialize(message).unwrap();
+        PaddingPolicy::default().pad(&mut plaintext);
+        let ciphertext = ChaCha20Poly1305::new(&Key::from(key))
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &associated_data(to, ephemeral_key.as_bytes()),
+                },
+            )
+            .unwrap();
+        SealedEnvelope {
+            to: to.to_string(),
+            ephemeral_key: *ephemeral_key.as_bytes(),
+            nonce,
+            ciphertext,
+        }
+    }
+
+    #[test]
+    fn opens_sealed_message() {
+        let bob = StaticSecret::new(&mut OsRng);
+        let message = message_to("bob");
+        let envelope =
+            SealedEnvelope::seal(&message, &PublicKey::from(&bob), &PaddingPolicy::default())
+                .unwrap();
+        assert_eq!(envelope.to(), "bob");
+        assert_eq!(envelope.open(&bob).unwrap(), message);
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let bob = StaticSecret::new(&mut OsRng);
+        let envelope = SealedEnvelope::seal(
+            &message_to("bob"),
+            &PublicKey::from(&bob),
+            &PaddingPolicy::default(),
+        )
+        .unwrap();
+        assert!(envelope.open(&StaticSecret::new(&mut OsRng)).is_err());
+    }
+
+    #[test]
+    fn rejects_changed_recipient() {
+        let bob = StaticSecret::new(&mut OsRng);
+        let mut envelope = SealedEnvelope::seal(
+            &message_to("bob"),
+            &PublicKey::from(&bob),
+            &PaddingPolicy::default(),
+        )
+        .unwrap();
+        envelope.to = "carol".to_string();
+        assert!(envelope.open(&bob).is_err());
+    }
+
+    #[test]
+    fn rejects_forwarded_message() {
+        let carol = StaticSecret::new(&mut OsRng);
+        let envelope = reseal(&message_to("bob"), "carol", &PublicKey::from(&carol));
+        assert!(envelope.open(&carol).is_err());
+        // The same envelope addressed to the inner recipient opens
+        let envelope = reseal(&message_to("carol"), "carol", &PublicKey::from(&carol));
+        assert!(envelope.open(&carol).is_ok());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::ratchet::MAX_SKIP;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Max number of missing sequence numbers remembered, as many as the ratchet keeps
+/// skipped message keys for. Older ones are treated as duplicates if they still arrive.
+pub const MAX_MISSING: usize = 2 * MAX_SKIP as usize;
+
+/// How a received sequence number relates to the ones received before it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SequenceStatus {
+    /// The next message in order
+    InOrder,
+    /// Messages were skipped, holds how many are missing
+    Gap(u64),
+    /// A message that was missing has arrived late
+    OutOfOrder,
+    /// The sequence number has been received before
+    Duplicate,
+}
+
+/// Sequence numbers received from a peer in a session. Every message carries the
+/// number of messages the sender sent before it, encrypted along with the content
+/// so it can't be changed by the server.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct SequenceTracker {
+    next: u64,
+    // Sequence numbers that were skipped over and haven't arrived yet
+    missing: BTreeSet<u64>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        SequenceTracker::default()
+    }
+
+    /// True until the first sequence number has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.next == 0
+    }
+
+    /// Records the sequence number, nothing changes if it's a duplicate. Fails for
+    /// `u64::MAX` since no number could follow it, no honest peer gets that far.
+    pub fn check(&mut self, sequence: u64) -> Result<SequenceStatus> {
+        if sequence < self.next {
+            return Ok(if self.missing.remove(&sequence) {
+                SequenceStatus::OutOfOrder
+            } else {
+                SequenceStatus::Duplicate
+            });
+        }
+        let next = sequence
+            .checked_add(1)
+            .ok_or("Sequence number out of range")?;
+        let missed = sequence - self.next;
+        if missed > 0 {
+            let first_kept = self.next.max(sequence.saturating_sub(MAX_MISSING as u64));
+            self.missing.extend(first_kept..sequence);
+            while self.missing.len() > MAX_MISSING {
+                self.missing.pop_first();
+            }
+        }
+        self.next = next;
+        Ok(if missed == 0 {
+            SequenceStatus::InOrder
+        } else {
+            SequenceStatus::Gap(missed)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_gaps_late_and_duplicate_messages() {
+        let mut tracker = SequenceTracker::new();
+        assert!(tracker.is_empty());
+        assert_eq!(tracker.check(0).unwrap(), SequenceStatus::InOrder);
+        assert!(!tracker.is_empty());
+        assert_eq!(tracker.check(3).unwrap(), SequenceStatus::Gap(2));
+        assert_eq!(tracker.check(4).unwrap(), SequenceStatus::InOrder);
+        assert_eq!(tracker.check(2).unwrap(), SequenceStatus::OutOfOrder);
+        assert_eq!(tracker.check(2).unwrap(), SequenceStatus::Duplicate);
+        assert_eq!(tracker.check(0).unwrap(), SequenceStatus::Duplicate);
+        assert_eq!(tracker.check(1).unwrap(), SequenceStatus::OutOfOrder);
+    }
+
+    #[test]
+    fn forgets_missing_numbers_beyond_the_limit() {
+        let mut tracker = SequenceTracker::new();
+        let last = 2 * MAX_MISSING as u64;
+        assert_eq!(tracker.check(last).unwrap(), SequenceStatus::Gap(last));
+        assert_eq!(tracker.check(0).unwrap(), SequenceStatus::Duplicate);
+        assert_eq!(tracker.check(last - 1).unwrap(), SequenceStatus::OutOfOrder);
+    }
+
+    #[test]
+    fn rejects_last_sequence_number() {
+        let mut tracker = SequenceTracker::new();
+        assert!(tracker.check(u64::MAX).is_err());
+        assert!(tracker.is_empty());
+        assert_eq!(tracker.check(0).unwrap(), SequenceStatus::InOrder);
+    }
+}
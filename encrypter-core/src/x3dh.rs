@@ -0,0 +1,314 @@
+use crate::identity::{self, SignedPublicKey, IDENTITY_KEY_SIZE};
+use crate::keys::KEY_SIZE;
+use crate::ratchet::Session;
+use crate::Result;
+use ed25519_dalek::{Keypair, Signer};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Max number of one-time prekeys the server keeps for a single account
+pub const MAX_ONE_TIME_PREKEYS: usize = 100;
+const SIGNED_PREKEY_LABEL: &[u8] = b"encrypter signed prekey v1";
+const X3DH_LABEL: &[u8] = b"encrypter x3dh v1";
+
+/// Medium-term X3DH key signed with the owner's identity key, replaced every
+/// time the client logs in.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct SignedPrekey {
+    pub key_id: u32,
+    pub public_key: [u8; 32],
+    signature: Vec<u8>,
+}
+
+impl SignedPrekey {
+    pub fn sign(key_id: u32, public_key: &PublicKey, identity: &Keypair) -> Self {
+        let signature = identity.sign(&signed_data(key_id, public_key.as_bytes()));
+        SignedPrekey {
+            key_id,
+            public_key: *public_key.as_bytes(),
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    pub fn verify(&self, identity_key: &[u8; IDENTITY_KEY_SIZE]) -> Result<()> {
+        identity::verify_signature(
+            identity_key,
+            &signed_data(self.key_id, &self.public_key),
+            &self.signature,
+        )
+        .map_err(|_| "Invalid signature on signed prekey".into())
+    }
+}
+
+/// Single use X3DH key, the server hands out each of them at most once
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub struct OneTimePrekey {
+    pub key_id: u32,
+    pub public_key: [u8; 32],
+}
+
+/// Everything needed to start a conversation with a peer without it being online
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct PrekeyBundle {
+    pub public_key: SignedPublicKey,
+    pub signed_prekey: SignedPrekey,
+    /// Missing if the peer has run out of one-time prekeys
+    pub one_time_prekey: Option<OneTimePrekey>,
+}
+
+impl PrekeyBundle {
+    /// Checks that every key in the bundle was signed by the same identity key for `id`
+    pub fn verify(&self, id: &str) -> Result<()> {
+        self.public_key.verify(id)?;
+        self.signed_prekey.verify(&self.public_key.identity_key)
+    }
+}
+
+/// Attached to every message of a conversation started from a prekey bundle until the
+/// peer replies, so it can derive the same session even if it misses the first message.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct InitialHeader {
+    pub sender_key: SignedPublicKey,
+    pub ephemeral_key: [u8; 32],
+    pub signed_prekey_id: u32,
+    pub one_time_prekey_id: Option<u32>,
+}
+
+/// Starts a session with the owner of the bundle. The returned session includes
+/// an `InitialHeader` in its messages until the first reply arrives.
+pub fn initiate(
+    own_secret: &StaticSecret,
+    own_key: &SignedPublicKey,
+    peer_id: &str,
+    bundle: &PrekeyBundle,
+) -> Result<Session> {
+    bundle.verify(peer_id)?;
+    let ephemeral = StaticSecret::new(&mut OsRng);
+    let peer_identity = PublicKey::from(bundle.public_key.public_key);
+    let signed_prekey = PublicKey::from(bundle.signed_prekey.public_key);
+    let mut dh_outputs = vec![
+        own_secret.diffie_hellman(&signed_prekey),
+        ephemeral.diffie_hellman(&peer_identity),
+        ephemeral.diffie_hellman(&signed_prekey),
+    ];
+    if let Some(one_time_prekey) = &bundle.one_time_prekey {
+        dh_outputs.push(ephemeral.diffie_hellman(&PublicKey::from(one_time_prekey.public_key)));
+    }
+    let shared_secret = derive_shared_secret(
+        dh_outputs.iter().map(|output| output.as_bytes()),
+        &own_key.public_key,
+        &bundle.public_key.public_key,
+    );
+    let initial_header = InitialHeader {
+        sender_key: own_key.clone(),
+        ephemeral_key: *PublicKey::from(&ephemeral).as_bytes(),
+        signed_prekey_id: bundle.signed_prekey.key_id,
+        one_time_prekey_id: bundle.one_time_prekey.map(|prekey| prekey.key_id),
+    };
+    Ok(Session::initiate(
+        shared_secret,
+        signed_prekey,
+        initial_header,
+    ))
+}
+
+/// Derives the session started by `from`. The caller looks up the prekeys named in the
+/// header and must only delete the one-time prekey once a message has been decrypted.
+pub fn respond(
+    own_secret: &StaticSecret,
+    own_public_key: &[u8; 32],
+    signed_prekey: &StaticSecret,
+    one_time_prekey: Option<&StaticSecret>,
+    from: &str,
+    header: &InitialHeader,
+) -> Result<Session> {
+    header.sender_key.verify(from)?;
+    if header.one_time_prekey_id.is_some() != one_time_prekey.is_some() {
+        return Err("Missing one-time prekey".into());
+    }
+    let peer_identity = PublicKey::from(header.sender_key.public_key);
+    let ephemeral = PublicKey::from(header.ephemeral_key);
+    let mut dh_outputs = vec![
+        signed_prekey.diffie_hellman(&peer_identity),
+        own_secret.diffie_hellman(&ephemeral),
+        signed_prekey.diffie_hellman(&ephemeral),
+    ];
+    if let Some(one_time_prekey) = one_time_prekey {
+        dh_outputs.push(one_time_prekey.diffie_hellman(&ephemeral));
+    }
+    let shared_secret = derive_shared_secret(
+        dh_outputs.iter().map(|output| output.as_bytes()),
+        &header.sender_key.public_key,
+        own_public_key,
+    );
+    Ok(Session::respond(shared_secret, signed_prekey.clone()))
+}
+
+// Both identity keys go into the info so the secret is bound to the two parties,
+// the initiator's key comes first.
+fn derive_shared_secret<'a>(
+    dh_outputs: impl Iterator<Item = &'a [u8; 32]>,
+    initiator_key: &[u8; 32],
+    responder_key: &[u8; 32],
+) -> [u8; KEY_SIZE] {
+    // Padding with 0xFF keeps the input distinct from an X25519 output, as in X3DH
+    let mut input_key_material = vec![0xFF; 32];
+    for output in dh_outputs {
+        input_key_material.extend_from_slice(output);
+    }
+    let mut info = Vec::with_capacity(X3DH_LABEL.len() + 64);
+    info.extend_from_slice(X3DH_LABEL);
+    info.extend_from_slice(initiator_key);
+    info.extend_from_slice(responder_key);
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&[0; KEY_SIZE]), &input_key_material);
+    let mut shared_secret = [0; KEY_SIZE];
+    hkdf.expand(&info, &mut shared_secret)
+        .expect("Output size is valid for HKDF-SHA256");
+    shared_secret
+}
+
+fn signed_data(key_id: u32, public_key: &[u8; 32]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(SIGNED_PREKEY_LABEL.len() + 4 + 32);
+    data.extend_from_slice(SIGNED_PREKEY_LABEL);
+    data.extend_from_slice(&key_id.to_be_bytes());
+    data.extend_from_slice(public_key);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::padding::PaddingPolicy;
+    use crate::{EncryptedMessage, Message, MessageBody, MessageKind};
+
+    struct Party {
+        id: &'static str,
+        identity: Keypair,
+        secret: StaticSecret,
+    }
+
+    impl Party {
+        fn new(id: &'static str) -> Self {
+            Party {
+                id,
+                identity: Keypair::generate(&mut OsRng),
+                secret: StaticSecret::new(&mut OsRng),
+            }
+        }
+
+        fn signed_key(&self) -> SignedPublicKey {
+            SignedPublicKey::sign(self.id, &PublicKey::from(&self.secret), &self.identity)
+        }
+    }
+
+    fn encrypt(session: &mut Session, from: &str, to: &str, text: &str) -> EncryptedMessage {
+        let body = MessageBody::new(&MessageKind::Text(text.to_string())).unwrap();
+        let message = Message::new(from.to_string(), to.to_string(), body);
+        session.encrypt(message, &PaddingPolicy::default()).unwrap()
+    }
+
+    fn text(session: &mut Session, message: EncryptedMessage) -> String {
+        match session.decrypt(message).unwrap().0.body.kind().unwrap() {
+            MessageKind::Text(text) => text,
+            _ => panic!("Expected a text message"),
+        }
+    }
+
+    fn agree(use_one_time_prekey: bool) {
+        let alice = Party::new("alice");
+        let bob = Party::new("bob");
+        let signed_prekey = StaticSecret::new(&mut OsRng);
+        let one_time_prekey = StaticSecret::new(&mut OsRng);
+        let bundle = PrekeyBundle {
+            public_key: bob.signed_key(),
+            signed_prekey: SignedPrekey::sign(1, &PublicKey::from(&signed_prekey), &bob.identity),
+            one_time_prekey: if use_one_time_prekey {
+                Some(OneTimePrekey {
+                    key_id: 2,
+                    public_key: *PublicKey::from(&one_time_prekey).as_bytes(),
+                })
+            } else {
+                None
+            },
+        };
+
+        let mut alice_session =
+            initiate(&alice.secret, &alice.signed_key(), "bob", &bundle).unwrap();
+        let first = encrypt(&mut alice_session, "alice", "bob", "hello");
+        let header = first.initial_header().unwrap().clone();
+        assert_eq!(header.signed_prekey_id, 1);
+        assert_eq!(header.one_time_prekey_id.is_some(), use_one_time_prekey);
+
+        let mut bob_session = respond(
+            &bob.secret,
+            &bob.signed_key().public_key,
+            &signed_prekey,
+            Some(&one_time_prekey).filter(|_| use_one_time_prekey),
+            "alice",
+            &header,
+        )
+        .unwrap();
+        assert_eq!(text(&mut bob_session, first), "hello");
+        let reply = encrypt(&mut bob_session, "bob", "alice", "hi");
+        assert_eq!(text(&mut alice_session, reply), "hi");
+        // The initial header is dropped once the peer has replied
+        let next = encrypt(&mut alice_session, "alice", "bob", "bye");
+        assert!(next.initial_header().is_none());
+        assert_eq!(text(&mut bob_session, next), "bye");
+    }
+
+    #[test]
+    fn agrees_on_session_with_one_time_prekey() {
+        agree(true);
+    }
+
+    #[test]
+    fn agrees_on_session_without_one_time_prekey() {
+        agree(false);
+    }
+
+    #[test]
+    fn rejects_prekey_signed_by_another_identity() {
+        let alice = Party::new("alice");
+        let bob = Party::new("bob");
+        let mallory = Party::new("mallory");
+        let bundle = PrekeyBundle {
+            public_key: bob.signed_key(),
+            signed_prekey: SignedPrekey::sign(
+                1,
+                &PublicKey::from(&StaticSecret::new(&mut OsRng)),
+                &mallory.identity,
+            ),
+            one_time_prekey: None,
+        };
+        assert!(initiate(&alice.secret, &alice.signed_key(), "bob", &bundle).is_err());
+        // The key itself is also bound to the id
+        assert!(bundle.public_key.verify("mallory").is_err());
+    }
+
+    #[test]
+    fn rejects_missing_one_time_prekey() {
+        let alice = Party::new("alice");
+        let bob = Party::new("bob");
+        let signed_prekey = StaticSecret::new(&mut OsRng);
+        let header = InitialHeader {
+            sender_key: alice.signed_key(),
+            ephemeral_key: *PublicKey::from(&StaticSecret::new(&mut OsRng)).as_bytes(),
+            signed_prekey_id: 1,
+            one_time_prekey_id: Some(2),
+        };
+        let result = respond(
+            &bob.secret,
+            &bob.signed_key().public_key,
+            &signed_prekey,
+            None,
+            "alice",
+            &header,
+        );
+        assert!(result.is_err());
+    }
+}
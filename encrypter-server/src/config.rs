@@ -0,0 +1,58 @@
+use std::env;
+use std::time::Duration;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:1337";
+const DEFAULT_NAME: &str = "Encrypter server";
+const DEFAULT_RATE_LIMIT: u32 = 20;
+const DEFAULT_QUEUE_LIMIT: usize = 100;
+const DEFAULT_QUEUE_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_DB_PATH: &str = "server_db";
+
+/// Server settings, read from `ENCRYPTER_*` environment variables with defaults
+/// for everything that isn't set.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub addr: String,
+    pub name: String,
+    pub motd: String,
+    /// Max number of messages per second from a single connection
+    pub rate_limit: u32,
+    /// Max number of messages queued for a single offline peer
+    pub queue_limit: usize,
+    /// How long messages for offline peers are kept
+    pub queue_ttl: Duration,
+    /// Where accounts and queued messages are stored, `None` keeps everything in memory.
+    /// Set `ENCRYPTER_DB_PATH` to an empty string to run without a database.
+    pub db_path: Option<String>,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        ServerConfig {
+            addr: env::var("ENCRYPTER_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_string()),
+            name: env::var("ENCRYPTER_SERVER_NAME").unwrap_or_else(|_| DEFAULT_NAME.to_string()),
+            motd: env::var("ENCRYPTER_MOTD").unwrap_or_default(),
+            rate_limit: parse_var("ENCRYPTER_RATE_LIMIT").unwrap_or(DEFAULT_RATE_LIMIT),
+            queue_limit: parse_var("ENCRYPTER_QUEUE_LIMIT").unwrap_or(DEFAULT_QUEUE_LIMIT),
+            queue_ttl: Duration::from_secs(
+                parse_var("ENCRYPTER_QUEUE_TTL").unwrap_or(DEFAULT_QUEUE_TTL_SECS),
+            ),
+            db_path: match env::var("ENCRYPTER_DB_PATH") {
+                Ok(path) if path.is_empty() => None,
+                Ok(path) => Some(path),
+                Err(_) => Some(DEFAULT_DB_PATH.to_string()),
+            },
+        }
+    }
+}
+
+fn parse_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            warn!("Ignoring invalid value {} for {}", value, name);
+            None
+        }
+    }
+}
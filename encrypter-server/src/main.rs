@@ -3,26 +3,60 @@ extern crate log;
 use async_std::{
     io::BufReader,
     io::ReadExt,
-    net::{TcpListener, TcpStream},
+    net::{SocketAddr, TcpListener, TcpStream},
     prelude::*,
     task,
 };
-use encrypter_core::Protocol;
+use encrypter_core::error::{ErrorCode, ServerError};
+use encrypter_core::framing::{encode_frame, FrameDecoder, FrameError, MAX_FRAME_SIZE};
+use encrypter_core::group::{GroupMessage, MAX_GROUP_MEMBERS};
+use encrypter_core::handshake::{self, ServerHello};
+use encrypter_core::identity::{self, KeyChange, SignedPublicKey};
+use encrypter_core::x3dh::{PrekeyBundle, MAX_ONE_TIME_PREKEYS};
 use encrypter_core::Result;
-use encrypter_core::MESSAGE_PACKET_SIZE;
+use encrypter_core::{is_valid_id, ClientMessage, ServerMessage};
 use futures::{channel::mpsc, FutureExt, SinkExt, StreamExt};
 use simplelog::*;
+use std::collections::HashMap;
 use std::fs::File;
+use std::sync::Arc;
 type Receiver<T> = mpsc::UnboundedReceiver<T>;
 type Sender<T> = mpsc::UnboundedSender<T>;
 
+const READ_BUFFER_SIZE: usize = 4096;
+
+mod config;
+mod offline_queue;
 mod peer;
+mod rate_limit;
+mod session;
+mod storage;
+use config::ServerConfig;
+use offline_queue::{OfflineQueue, QueueError};
 use peer::Peer;
 use peer::PeerSet;
+use rate_limit::RateLimiter;
+use session::Session;
+use storage::{DiskStorage, MemoryStorage, Prekeys, QueuedPayload, Storage};
+
+/// Events handled by the message broker, either a message from a client
+/// or a signal from the listener task of the connection.
+#[derive(Debug)]
+enum BrokerEvent {
+    Client(ClientMessage),
+    // Errors detected by the listener task are sent back to the client through the broker
+    // so the broker remains the only one writing to registered connections.
+    Error(ServerError),
+    ConnectionClosed,
+}
+
 #[derive(Debug)]
 struct NetEvent {
-    pub protocol_message: Protocol,
+    pub event: BrokerEvent,
     pub stream: TcpStream,
+    // Captured when the connection is accepted since the address of
+    // a closed socket can't be looked up anymore
+    pub addr: SocketAddr,
 }
 
 fn main() -> Result<()> {
@@ -36,25 +70,36 @@ fn main() -> Result<()> {
         ),
     ])
     .expect("Failed to initalize logger");
-    task::block_on(accept_connections("127.0.0.1:1337"))
+    task::block_on(accept_connections(Arc::new(ServerConfig::from_env())))
 }
 
-async fn accept_connections(addr: &str) -> Result<()> {
-    let tcp_listener = TcpListener::bind(addr).await?;
+async fn accept_connections(config: Arc<ServerConfig>) -> Result<()> {
+    let tcp_listener = TcpListener::bind(&config.addr).await?;
+    let storage: Box<dyn Storage> = match &config.db_path {
+        Some(path) => {
+            info!("Using database at {}", path);
+            Box::new(DiskStorage::open(path)?)
+        }
+        None => {
+            warn!("No database path set, nothing will be saved across restarts");
+            Box::new(MemoryStorage::new())
+        }
+    };
+    let offline_queue = OfflineQueue::new(config.queue_limit, config.queue_ttl);
     let (sender, receiver) = mpsc::unbounded::<NetEvent>();
-    task::spawn(message_broker(receiver));
+    task::spawn(message_broker(receiver, storage, offline_queue));
     let mut incoming = tcp_listener.incoming();
     while let Some(connection) = incoming.next().await {
         let stream = connection?;
         info!("New connection from: {}", stream.peer_addr()?);
-        spawn_listener_task(sender.clone(), stream);
+        spawn_listener_task(sender.clone(), stream, config.clone());
     }
     Ok(())
 }
 
-fn spawn_listener_task(sender: Sender<NetEvent>, stream: TcpStream) {
+fn spawn_listener_task(sender: Sender<NetEvent>, stream: TcpStream, config: Arc<ServerConfig>) {
     task::spawn(async move {
-        if let Err(e) = listen_to_traffic(sender, stream).await {
+        if let Err(e) = listen_to_traffic(sender, stream, config).await {
             error!("Error parsing incomming traffic: {:#?}", e);
         }
     });
@@ -62,21 +107,98 @@ fn spawn_listener_task(sender: Sender<NetEvent>, stream: TcpStream) {
 
 // This is where all the magic happens, there is only one message broker task on each server
 // that means it's possible (but not scalable) to keep all peer info in memory.
-async fn message_broker(mut receiver: Receiver<NetEvent>) -> Result<()> {
+async fn message_broker(
+    mut receiver: Receiver<NetEvent>,
+    mut storage: Box<dyn Storage>,
+    offline_queue: OfflineQueue,
+) -> Result<()> {
     let mut peers = PeerSet::new();
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
     // continue to wait for new NetEvents
     // fuse makes sure that the future won't be polled again, this shouldn't happen (I think)
     // but it's good to make sure either way.
     while let Some(event) = receiver.next().fuse().await {
-        match event.protocol_message {
-            Protocol::NewConnection(id, public_key) => {
-                let peer = Peer::new(id, event.stream.clone(), public_key);
+        let session = sessions.entry(event.addr).or_insert_with(Session::new);
+        match event.event {
+            BrokerEvent::Client(ClientMessage::NewConnection(id, public_key)) => {
+                if let Some(registered_id) = session.registered_id() {
+                    warn!(
+                        "Connection {} already registered as {}, ignoring registration as {}",
+                        event.addr, registered_id, id
+                    );
+                    let error = ServerError::new(ErrorCode::Unauthorized)
+                        .with_details(format!("Already registered as {}", registered_id));
+                    send_to_peer(&event.stream, ServerMessage::Error(error)).await;
+                    continue;
+                }
+                if let Err(error) = validate_registration(&id, &public_key, &peers, &*storage) {
+                    warn!(
+                        "Rejected registration of {} from {}: {}",
+                        id, event.addr, error
+                    );
+                    send_to_peer(&event.stream, ServerMessage::Error(error)).await;
+                    continue;
+                }
+                let challenge = session
+                    .challenge(id, public_key)
+                    .expect("Session to not be registered");
+                send_to_peer(&event.stream, ServerMessage::Challenge(challenge)).await;
+            }
+            BrokerEvent::Client(ClientMessage::ChallengeResponse(signature)) => {
+                let pending = match session.take_pending() {
+                    Some(pending) => pending,
+                    None => {
+                        warn!(
+                            "Connection {} in state {:?} sent an unexpected challenge response",
+                            event.addr,
+                            session.state()
+                        );
+                        let error = ServerError::new(ErrorCode::Unauthorized)
+                            .with_details("No pending registration");
+                        send_to_peer(&event.stream, ServerMessage::Error(error)).await;
+                        continue;
+                    }
+                };
+                if let Err(err) = identity::verify_challenge(
+                    &pending.id,
+                    &pending.challenge,
+                    &pending.public_key.identity_key,
+                    &signature,
+                ) {
+                    warn!(
+                        "Registration of {} from {} failed the challenge: {}",
+                        pending.id, event.addr, err
+                    );
+                    let error = ServerError::new(ErrorCode::ChallengeFailed);
+                    send_to_peer(&event.stream, ServerMessage::Error(error)).await;
+                    continue;
+                }
+                // Another connection might have registered the id while waiting for the response
+                let validation =
+                    validate_registration(&pending.id, &pending.public_key, &peers, &*storage)
+                        .and_then(|_| {
+                            storage
+                                .save_account(&pending.id, &pending.public_key)
+                                .map_err(|err| {
+                                    error!("Couldn't save account {}: {}", pending.id, err);
+                                    ServerError::new(ErrorCode::Internal)
+                                })
+                        });
+                if let Err(error) = validation {
+                    warn!(
+                        "Rejected registration of {} from {}: {}",
+                        pending.id, event.addr, error
+                    );
+                    send_to_peer(&event.stream, ServerMessage::Error(error)).await;
+                    continue;
+                }
+                let peer = Peer::new(pending.id, event.stream.clone(), pending.public_key);
                 // This is sent to all peers BEFORE adding the new connection, since
                 // every peer except the recently added should receive the update is_ok
                 // is checked to make sure the peer will be able to be addded to the PeerSet
                 if peer.get_addr().is_ok() {
                     send_to_all_peers(
-                        Protocol::NewConnection(peer.peer_id.clone(), peer.public_key),
+                        ServerMessage::NewConnection(peer.peer_id.clone(), peer.public_key.clone()),
                         &peers,
                     )
                     .await;
@@ -84,46 +206,212 @@ async fn message_broker(mut receiver: Receiver<NetEvent>) -> Result<()> {
                 let added_peer = peer.peer_id.clone();
                 match peers.insert(peer) {
                     Ok(_) => {
+                        session
+                            .register(added_peer.clone())
+                            .expect("Session to be unregistered");
                         // unwrap is safe since peer was just added
-                        send_peer_list(
-                            peers
-                                .find_by_id(&added_peer)
-                                .expect("Peer to be added to peerset"),
-                            &peers,
-                        )
-                        .await;
+                        let peer = peers
+                            .find_by_id(&added_peer)
+                            .expect("Peer to be added to peerset");
+                        send_peer_list(peer, &peers).await;
+                        // Queued messages are delivered after the peer list so the
+                        // client knows about the senders that are still online
+                        let mut queued_messages = offline_queue
+                            .pending(&*storage, &added_peer)
+                            .unwrap_or_else(|err| {
+                                error!("Couldn't load queued messages for {}: {}", added_peer, err);
+                                Vec::new()
+                            });
+                        if queued_messages.is_empty() {
+                            continue;
+                        }
+                        info!(
+                            "Delivering {} queued message(s) to {}",
+                            queued_messages.len(),
+                            added_peer
+                        );
+                        // Stops at the first failure so the rest stay queued in order
+                        let mut delivered = 0;
+                        for queued in &queued_messages {
+                            if !send_to_peer(&peer.tcp_stream, queued.message.clone().into()).await
+                            {
+                                break;
+                            }
+                            delivered += 1;
+                        }
+                        let undelivered = queued_messages.split_off(delivered);
+                        if let Err(err) =
+                            offline_queue.finish_delivery(&mut *storage, &added_peer, undelivered)
+                        {
+                            error!(
+                                "Couldn't update queued messages for {}: {}",
+                                added_peer, err
+                            );
+                        }
                     }
                     Err(err) => {
                         error!("Error: {}", err);
                     }
                 }
             }
-            Protocol::Disconnect(id) => {
-                peers.remove_by_id(&id);
-                send_to_all_peers(Protocol::Disconnect(id), &peers).await;
+            BrokerEvent::Client(ClientMessage::UploadPrekeys(signed_prekey, one_time_prekeys)) => {
+                let result = match session.registered_id().and_then(|id| peers.find_by_id(id)) {
+                    Some(peer) => store_prekeys(
+                        &mut *storage,
+                        peer,
+                        Prekeys {
+                            signed_prekey,
+                            one_time_prekeys,
+                        },
+                    ),
+                    None => Err(ServerError::new(ErrorCode::NotRegistered)),
+                };
+                if let Err(error) = result {
+                    warn!("Rejected prekeys from {}: {}", event.addr, error);
+                    send_to_peer(&event.stream, ServerMessage::Error(error)).await;
+                }
             }
-            // TODO: Split up internal and external Protocol?
-            Protocol::InternalRemoveConnection => {
-                if let Ok(socket_addr) = event.stream.peer_addr() {
-                    if let Some(removed_peer) = peers.remove_by_ip(&socket_addr) {
-                        send_to_all_peers(Protocol::Disconnect(removed_peer.peer_id), &peers).await;
+            BrokerEvent::Client(ClientMessage::FetchPrekeyBundle(id)) => {
+                let reply = if session.registered_id().is_some() {
+                    match take_prekey_bundle(&mut *storage, &id) {
+                        Ok(bundle) => ServerMessage::PrekeyBundle(id, Box::new(bundle)),
+                        Err(error) => ServerMessage::Error(error),
                     }
                 } else {
-                    error!("No socket addr was found in net event: {:?}", event);
+                    ServerMessage::Error(ServerError::new(ErrorCode::NotRegistered))
+                };
+                send_to_peer(&event.stream, reply).await;
+            }
+            BrokerEvent::Client(ClientMessage::FetchPublicKey(id)) => {
+                let reply = if session.registered_id().is_some() {
+                    match account_key(&*storage, &id) {
+                        Ok(public_key) => ServerMessage::PublicKey(id, public_key),
+                        Err(error) => ServerMessage::Error(error),
+                    }
+                } else {
+                    ServerMessage::Error(ServerError::new(ErrorCode::NotRegistered))
+                };
+                send_to_peer(&event.stream, reply).await;
+            }
+            BrokerEvent::Client(ClientMessage::RotateKey(reference, key_change)) => {
+                let result = match session.registered_id() {
+                    Some(id) => rotate_key(&mut *storage, &mut peers, id, &key_change)
+                        .map(|_| id.to_string()),
+                    None => Err(ServerError::new(ErrorCode::NotRegistered)),
+                };
+                match result {
+                    Ok(id) => {
+                        info!("{} rotated its key", id);
+                        // Includes the sender, it's the acknowledgement of the change
+                        send_to_all_peers(ServerMessage::KeyChange(id, key_change), &peers).await;
+                    }
+                    Err(error) => {
+                        warn!("Rejected key change from {}: {}", event.addr, error);
+                        let error = error.with_reference(reference);
+                        send_to_peer(&event.stream, ServerMessage::Error(error)).await;
+                    }
+                }
+            }
+            BrokerEvent::Client(ClientMessage::Disconnect(id)) => {
+                // A connection is only allowed to disconnect itself
+                if session.registered_id() == Some(id.as_str()) {
+                    peers.remove_by_id(&id);
+                    session.unregister();
+                    send_to_all_peers(ServerMessage::Disconnect(id), &peers).await;
+                } else {
+                    warn!(
+                        "Connection {} in state {:?} tried to disconnect {}",
+                        event.addr,
+                        session.state(),
+                        id
+                    );
+                    let error = ServerError::new(ErrorCode::Unauthorized)
+                        .with_details(format!("Can't disconnect {}", id));
+                    send_to_peer(&event.stream, ServerMessage::Error(error)).await;
+                }
+            }
+            BrokerEvent::ConnectionClosed => {
+                sessions.remove(&event.addr);
+                if let Some(removed_peer) = peers.remove_by_ip(&event.addr) {
+                    send_to_all_peers(ServerMessage::Disconnect(removed_peer.peer_id), &peers)
+                        .await;
                 }
             }
-            Protocol::Message(encrypted_message) => {
-                let (_from, to) = encrypted_message.get_info();
-                if let Some(receiving_participant) = peers.find_by_id(to) {
-                    let mut receiveing_stream = &receiving_participant.tcp_stream;
-                    receiveing_stream
-                        .write_all(&bincode::serialize(&Protocol::Message(encrypted_message))?)
-                        .await?;
+            // The sender is sealed inside the envelope, it's only known that the
+            // connection is registered
+            BrokerEvent::Client(ClientMessage::Message(reference, envelope)) => {
+                let error = if session.registered_id().is_none() {
+                    warn!(
+                        "Dropped message from unregistered connection {}",
+                        event.addr
+                    );
+                    Some(ServerError::new(ErrorCode::NotRegistered))
+                } else if let Some(receiving_participant) = peers.find_by_id(envelope.to()) {
+                    send_to_peer(
+                        &receiving_participant.tcp_stream,
+                        ServerMessage::Message(envelope),
+                    )
+                    .await;
+                    None
                 } else {
-                    warn!("Message couldn't be sent, no peer with id {} connected", to);
+                    let to = envelope.to().to_string();
+                    queue_message(
+                        &mut *storage,
+                        &offline_queue,
+                        &to,
+                        QueuedPayload::Message(envelope),
+                    )
+                };
+                if let Some(error) = error {
+                    let error = error.with_reference(reference);
+                    send_to_peer(&event.stream, ServerMessage::Error(error)).await;
+                }
+            }
+            BrokerEvent::Client(ClientMessage::GroupMessage(
+                reference,
+                recipients,
+                group_message,
+            )) => {
+                let errors = match session.registered_id() {
+                    None => vec![ServerError::new(ErrorCode::NotRegistered)],
+                    Some(registered_id) if registered_id != group_message.from() => {
+                        warn!(
+                            "Dropped group message from {} claiming to be sent by {}",
+                            registered_id,
+                            group_message.from()
+                        );
+                        vec![
+                            ServerError::new(ErrorCode::Unauthorized).with_details(format!(
+                                "Can't send messages as {}",
+                                group_message.from()
+                            )),
+                        ]
+                    }
+                    Some(_) if recipients.len() >= MAX_GROUP_MEMBERS => {
+                        vec![ServerError::new(ErrorCode::TooManyRecipients)]
+                    }
+                    Some(_) => {
+                        fan_out_group_message(
+                            &mut *storage,
+                            &offline_queue,
+                            &peers,
+                            recipients,
+                            group_message,
+                        )
+                        .await
+                    }
+                };
+                for error in errors {
+                    let error = error.with_reference(reference);
+                    send_to_peer(&event.stream, ServerMessage::Error(error)).await;
                 }
             }
-            _ => {}
+            BrokerEvent::Error(error) => {
+                send_to_peer(&event.stream, ServerMessage::Error(error)).await;
+            }
+            BrokerEvent::Client(ClientMessage::Hello(_)) => {
+                warn!("Received handshake from a client that has already completed it");
+            }
         }
     }
     Ok(())
@@ -131,66 +419,404 @@ async fn message_broker(mut receiver: Receiver<NetEvent>) -> Result<()> {
 
 // This creates a shared pointer to each TcpConnection and sends the pointer together with
 // each message to the message broker who handles the actual propagation of messages.
-async fn listen_to_traffic(mut sender: Sender<NetEvent>, stream: TcpStream) -> Result<()> {
+async fn listen_to_traffic(
+    mut sender: Sender<NetEvent>,
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+) -> Result<()> {
     // The TcpStream doesn't require an Arc and is clonable since async-std internally uses an Arc
     // for the socket file descriptior.
     // This listens on incoming traffic but it's also needed when sending out messages
+    let addr = stream.peer_addr()?;
     let mut reader = BufReader::new(&stream);
-    let mut buffer = vec![0 as u8; MESSAGE_PACKET_SIZE];
-    loop {
+    let mut buffer = vec![0_u8; READ_BUFFER_SIZE];
+    let mut decoder = FrameDecoder::new();
+    let mut rate_limiter = RateLimiter::new(config.rate_limit);
+    let mut handshake_done = false;
+    'read: loop {
         match reader.read(&mut buffer).await {
             Ok(0) | Err(_) => {
                 // Probable disconnect from client
-                sender
-                    .send(NetEvent {
-                        protocol_message: Protocol::InternalRemoveConnection,
-                        stream: stream.clone(),
-                    })
-                    .await
-                    .unwrap_or_else(|err| error!("Couldn't send message over channel {}", err));
-
-                break Ok(());
-            }
-            Ok(n) => match bincode::deserialize::<Protocol>(&buffer[..n]) {
-                Ok(protocol_message) => {
-                    debug!("Protocol Message received: {:?}", protocol_message);
-                    sender
-                        .send(NetEvent {
-                            protocol_message,
-                            stream: stream.clone(),
-                        })
-                        .await
-                        .unwrap_or_else(|err| error!("Couldn't send message over channel {}", err));
-                }
-                Err(err) => {
-                    error!("Could not parse message from incomming traffic: {}", err);
+                break 'read;
+            }
+            Ok(n) => {
+                decoder.extend(&buffer[..n]);
+                // A single read might contain several frames or only part of one
+                loop {
+                    match decoder.decode::<ClientMessage>() {
+                        Ok(Some(client_message)) if !handshake_done => {
+                            if handshake(&stream, client_message, &config).await? {
+                                handshake_done = true;
+                            } else {
+                                break 'read;
+                            }
+                        }
+                        Ok(Some(client_message)) if !rate_limiter.allow() => {
+                            warn!("Rate limited {}, dropping {:?}", addr, client_message);
+                            let mut error = ServerError::new(ErrorCode::RateLimited);
+                            match client_message {
+                                ClientMessage::Message(reference, _)
+                                | ClientMessage::GroupMessage(reference, ..)
+                                | ClientMessage::RotateKey(reference, _) => {
+                                    error = error.with_reference(reference);
+                                }
+                                _ => {}
+                            }
+                            send_event(&mut sender, BrokerEvent::Error(error), &stream, addr).await;
+                        }
+                        Ok(Some(client_message)) => {
+                            debug!("Client Message received: {:?}", client_message);
+                            send_event(
+                                &mut sender,
+                                BrokerEvent::Client(client_message),
+                                &stream,
+                                addr,
+                            )
+                            .await;
+                        }
+                        Ok(None) => break,
+                        Err(err @ FrameError::Malformed(_)) => {
+                            error!("Could not parse message from incomming traffic: {}", err);
+                            let error = ServerError::new(ErrorCode::MalformedFrame)
+                                .with_details(err.to_string());
+                            send_event(&mut sender, BrokerEvent::Error(error), &stream, addr).await;
+                        }
+                        Err(err @ FrameError::TooLarge(_)) => {
+                            error!("Closing connection, stream is out of sync: {}", err);
+                            break 'read;
+                        }
+                    }
                 }
-            },
+            }
         }
     }
+    send_event(&mut sender, BrokerEvent::ConnectionClosed, &stream, addr).await;
+    Ok(())
 }
 
-async fn send_peer_list(target_peer: &Peer, peers: &PeerSet) {
-    let connected_peers = peers
+async fn send_event(
+    sender: &mut Sender<NetEvent>,
+    event: BrokerEvent,
+    stream: &TcpStream,
+    addr: SocketAddr,
+) {
+    sender
+        .send(NetEvent {
+            event,
+            stream: stream.clone(),
+            addr,
+        })
+        .await
+        .unwrap_or_else(|err| error!("Couldn't send message over channel {}", err));
+}
+
+// Every connection has to start with a handshake before any other message is accepted.
+// Returns false if the client was rejected and the connection should be closed.
+async fn handshake(
+    stream: &TcpStream,
+    message: ClientMessage,
+    config: &ServerConfig,
+) -> Result<bool> {
+    let reply = match message {
+        ClientMessage::Hello(hello) => match handshake::check_version(hello.protocol_version) {
+            Ok(()) => {
+                info!(
+                    "Handshake with client on protocol version {}, features: {:?}",
+                    hello.protocol_version, hello.features
+                );
+                ServerMessage::Welcome(ServerHello {
+                    protocol_version: handshake::PROTOCOL_VERSION,
+                    features: handshake::supported_features(),
+                    max_frame_size: MAX_FRAME_SIZE as u32,
+                    queue_ttl: config.queue_ttl.as_secs(),
+                    server_name: config.name.clone(),
+                    motd: config.motd.clone(),
+                })
+            }
+            Err(reason) => {
+                warn!("Rejected client: {}", reason);
+                ServerMessage::Error(
+                    ServerError::new(ErrorCode::UnsupportedVersion).with_details(reason),
+                )
+            }
+        },
+        unexpected => {
+            warn!("Expected handshake but received: {:?}", unexpected);
+            ServerMessage::Error(
+                ServerError::new(ErrorCode::HandshakeRequired)
+                    .with_details("The client is likely outdated"),
+            )
+        }
+    };
+    let accepted = matches!(reply, ServerMessage::Welcome(_));
+    let mut stream = stream;
+    stream.write_all(&encode_frame(&reply)?).await?;
+    Ok(accepted)
+}
+
+fn store_prekeys(
+    storage: &mut dyn Storage,
+    peer: &Peer,
+    mut prekeys: Prekeys,
+) -> std::result::Result<(), ServerError> {
+    if prekeys
+        .signed_prekey
+        .verify(&peer.public_key.identity_key)
+        .is_err()
+    {
+        return Err(ServerError::new(ErrorCode::InvalidKey)
+            .with_details("Invalid signature on signed prekey"));
+    }
+    if prekeys.one_time_prekeys.len() > MAX_ONE_TIME_PREKEYS {
+        warn!(
+            "{} uploaded {} one-time prekeys, only keeping {}",
+            peer.peer_id,
+            prekeys.one_time_prekeys.len(),
+            MAX_ONE_TIME_PREKEYS
+        );
+        prekeys.one_time_prekeys.truncate(MAX_ONE_TIME_PREKEYS);
+    }
+    storage
+        .save_prekeys(&peer.peer_id, &prekeys)
+        .map_err(|err| {
+            error!("Couldn't save prekeys for {}: {}", peer.peer_id, err);
+            ServerError::new(ErrorCode::Internal)
+        })
+}
+
+// The same ciphertext goes to every recipient, online or not. Returns an error for
+// every recipient it couldn't be delivered to.
+async fn fan_out_group_message(
+    storage: &mut dyn Storage,
+    offline_queue: &OfflineQueue,
+    peers: &PeerSet,
+    mut recipients: Vec<String>,
+    group_message: GroupMessage,
+) -> Vec<ServerError> {
+    recipients.sort();
+    recipients.dedup();
+    let mut errors = Vec::new();
+    for to in recipients
         .iter()
-        .map(|(id, peer)| (id.clone(), peer.public_key))
-        .collect::<Vec<(String, [u8; 32])>>();
-    let message = Protocol::PeerList(connected_peers);
-    if let Ok(message_buffer) = bincode::serialize(&message) {
-        let mut stream = &target_peer.tcp_stream;
+        .filter(|recipient| *recipient != group_message.from())
+    {
+        if let Some(peer) = peers.find_by_id(to) {
+            send_to_peer(
+                &peer.tcp_stream,
+                ServerMessage::GroupMessage(group_message.clone()),
+            )
+            .await;
+        } else if let Some(error) = queue_message(
+            storage,
+            offline_queue,
+            to,
+            QueuedPayload::GroupMessage(group_message.clone()),
+        ) {
+            errors.push(error);
+        }
+    }
+    errors
+}
+
+fn queue_message(
+    storage: &mut dyn Storage,
+    offline_queue: &OfflineQueue,
+    to: &str,
+    message: QueuedPayload,
+) -> Option<ServerError> {
+    match offline_queue.push(storage, to, message) {
+        Ok(()) => {
+            debug!("Queued message for offline peer {}", to);
+            None
+        }
+        Err(QueueError::UnknownRecipient) => {
+            warn!("Message couldn't be sent, no peer with id {}", to);
+            Some(ServerError::new(ErrorCode::UnknownRecipient).with_details(to))
+        }
+        Err(QueueError::QueueFull) => {
+            warn!("Message queue for offline peer {} is full", to);
+            Some(ServerError::new(ErrorCode::QueueFull).with_details(to))
+        }
+        Err(QueueError::Storage(err)) => {
+            error!("Couldn't queue message for {}: {}", to, err);
+            Some(ServerError::new(ErrorCode::Internal))
+        }
+    }
+}
+
+// The key the account was registered with, or rotated to
+fn account_key(
+    storage: &dyn Storage,
+    id: &str,
+) -> std::result::Result<SignedPublicKey, ServerError> {
+    storage
+        .account(id)
+        .map_err(|err| {
+            error!("Couldn't load account {}: {}", id, err);
+            ServerError::new(ErrorCode::Internal)
+        })?
+        .ok_or_else(|| ServerError::new(ErrorCode::UnknownRecipient).with_details(id))
+}
+
+// Every one-time prekey is only handed out once, the bundle is still usable
+// without one when they run out
+fn take_prekey_bundle(
+    storage: &mut dyn Storage,
+    id: &str,
+) -> std::result::Result<PrekeyBundle, ServerError> {
+    let internal_error = |err| {
+        error!("Couldn't load prekeys for {}: {}", id, err);
+        ServerError::new(ErrorCode::Internal)
+    };
+    let public_key = account_key(storage, id)?;
+    let mut prekeys = storage
+        .prekeys(id)
+        .map_err(internal_error)?
+        .ok_or_else(|| ServerError::new(ErrorCode::NoPrekeys).with_details(id))?;
+    let one_time_prekey = if prekeys.one_time_prekeys.is_empty() {
+        None
+    } else {
+        let one_time_prekey = prekeys.one_time_prekeys.remove(0);
+        storage.save_prekeys(id, &prekeys).map_err(internal_error)?;
+        Some(one_time_prekey)
+    };
+    Ok(PrekeyBundle {
+        public_key,
+        signed_prekey: prekeys.signed_prekey,
+        one_time_prekey,
+    })
+}
+
+// The new key has to come from the identity key bound to the account and replace the
+// key the peer is currently using
+fn rotate_key(
+    storage: &mut dyn Storage,
+    peers: &mut PeerSet,
+    id: &str,
+    key_change: &KeyChange,
+) -> std::result::Result<(), ServerError> {
+    let peer = peers
+        .find_by_id_mut(id)
+        .ok_or_else(|| ServerError::new(ErrorCode::NotRegistered))?;
+    if key_change.verify(id).is_err() {
+        return Err(ServerError::new(ErrorCode::InvalidKey));
+    }
+    if key_change.new_key.identity_key != peer.public_key.identity_key {
+        return Err(ServerError::new(ErrorCode::Unauthorized)
+            .with_details("The key change is signed by another identity key"));
+    }
+    if key_change.previous_key != peer.public_key.public_key {
+        return Err(ServerError::new(ErrorCode::InvalidKey)
+            .with_details("The key change doesn't replace the current key"));
+    }
+    storage
+        .save_account(id, &key_change.new_key)
+        .map_err(|err| {
+            error!("Couldn't save account {}: {}", id, err);
+            ServerError::new(ErrorCode::Internal)
+        })?;
+    peer.public_key = key_change.new_key.clone();
+    Ok(())
+}
+
+fn validate_registration(
+    id: &str,
+    public_key: &SignedPublicKey,
+    peers: &PeerSet,
+    storage: &dyn Storage,
+) -> std::result::Result<(), ServerError> {
+    if !is_valid_id(id) {
+        return Err(ServerError::new(ErrorCode::InvalidId));
+    }
+    if public_key.verify(id).is_err() {
+        return Err(ServerError::new(ErrorCode::InvalidKey));
+    }
+    // The first registration binds the id to an identity key, the signed X25519 key
+    // may change between logins
+    match storage.account(id) {
+        Ok(Some(account)) if account.identity_key != public_key.identity_key => {
+            return Err(ServerError::new(ErrorCode::IdTaken)
+                .with_details("The id belongs to another identity key"));
+        }
+        Ok(_) => {}
+        Err(err) => {
+            error!("Couldn't look up account {}: {}", id, err);
+            return Err(ServerError::new(ErrorCode::Internal));
+        }
+    }
+    if peers.contains_id(id) {
+        return Err(ServerError::new(ErrorCode::IdTaken));
+    }
+    Ok(())
+}
+
+// Returns false if the message couldn't be sent
+async fn send_to_peer(stream: &TcpStream, message: ServerMessage) -> bool {
+    if let Ok(message_buffer) = encode_frame(&message) {
+        let mut stream = stream;
         if let Err(err) = stream.write_all(&message_buffer).await {
             error!(
-                "Error {}: Couldn't send message {:?}, to peer: {:?}",
-                err, message, target_peer
+                "Error {}: Couldn't send message {:?}, to ip: {:?}",
+                err,
+                message,
+                stream.peer_addr()
             );
+            return false;
         }
+        true
     } else {
         error!("Error: Couldn't serialize message: {:?}", message);
+        false
     }
 }
+
+async fn send_peer_list(target_peer: &Peer, peers: &PeerSet) {
+    let connected_peers = peers
+        .iter()
+        .map(|(id, peer)| (id.clone(), peer.public_key.clone()))
+        .collect::<Vec<(String, SignedPublicKey)>>();
+    let parts = match split_peer_list(connected_peers) {
+        Ok(parts) => parts,
+        Err(err) => {
+            error!("Couldn't split peer list: {}", err);
+            let error =
+                ServerError::new(ErrorCode::Internal).with_details("Couldn't send peer list");
+            send_to_peer(&target_peer.tcp_stream, ServerMessage::Error(error)).await;
+            return;
+        }
+    };
+    let last = parts.len() - 1;
+    for (index, part) in parts.into_iter().enumerate() {
+        let message = ServerMessage::PeerList(part, index == last);
+        if !send_to_peer(&target_peer.tcp_stream, message).await {
+            return;
+        }
+    }
+}
+
+// Splits the peers into parts that each fit in a single `PeerList` frame,
+// there is always at least one part
+fn split_peer_list(
+    peers: Vec<(String, SignedPublicKey)>,
+) -> Result<Vec<Vec<(String, SignedPublicKey)>>> {
+    let empty_size = bincode::serialized_size(&ServerMessage::PeerList(Vec::new(), true))?;
+    let max_size = MAX_FRAME_SIZE - empty_size as usize;
+    let mut parts = vec![Vec::new()];
+    let mut size = 0;
+    for peer in peers {
+        let peer_size = bincode::serialized_size(&peer)? as usize;
+        if size + peer_size > max_size {
+            parts.push(Vec::new());
+            size = 0;
+        }
+        size += peer_size;
+        parts.last_mut().expect("At least one part").push(peer);
+    }
+    Ok(parts)
+}
 // TODO: FuturesUnordered datastructure might be more efficent than join_all
-async fn send_to_all_peers(message: Protocol, peers: &PeerSet) {
-    if let Ok(message) = bincode::serialize(&message) {
+async fn send_to_all_peers(message: ServerMessage, peers: &PeerSet) {
+    if let Ok(message) = encode_frame(&message) {
         let handles = peers.values().map(|peer| {
             let msg = message.clone();
             let socket = peer.tcp_stream.clone();
@@ -210,3 +836,35 @@ async fn send_to_all_peers(message: Protocol, peers: &PeerSet) {
         error!("Error: Couldn't serialize message: {:?}", message);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    #[test]
+    fn splits_large_peer_list_into_frames() {
+        let identity = ed25519_dalek::Keypair::generate(&mut OsRng);
+        let public_key = PublicKey::from(&StaticSecret::new(&mut OsRng));
+        let peers: Vec<_> = (0..2000)
+            .map(|i| {
+                let id = i.to_string();
+                let signed_key = SignedPublicKey::sign(&id, &public_key, &identity);
+                (id, signed_key)
+            })
+            .collect();
+
+        let parts = split_peer_list(peers.clone()).unwrap();
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(encode_frame(&ServerMessage::PeerList(part.clone(), true)).is_ok());
+        }
+        assert_eq!(parts.concat(), peers);
+    }
+
+    #[test]
+    fn sends_one_part_for_small_peer_list() {
+        assert_eq!(split_peer_list(Vec::new()).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::storage::{QueuedMessage, QueuedPayload, Storage};
+use encrypter_core::Result;
+use std::time::Duration;
+
+/// Holds messages for peers that have an account but aren't connected right now.
+/// Messages are kept in the order they arrived and are dropped once they are
+/// older than the ttl.
+pub struct OfflineQueue {
+    limit: usize,
+    ttl: Duration,
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    UnknownRecipient,
+    QueueFull,
+    Storage(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl OfflineQueue {
+    pub fn new(limit: usize, ttl: Duration) -> Self {
+        OfflineQueue { limit, ttl }
+    }
+
+    /// Returns the messages queued for the peer that haven't expired, oldest first.
+    /// They stay queued until `finish_delivery` so nothing is lost if sending fails.
+    pub fn pending(&self, storage: &dyn Storage, id: &str) -> Result<Vec<QueuedMessage>> {
+        Ok(self.unexpired(storage.queued_messages(id)?))
+    }
+
+    /// Keeps only the messages from `pending` that couldn't be delivered
+    pub fn finish_delivery(
+        &self,
+        storage: &mut dyn Storage,
+        id: &str,
+        undelivered: Vec<QueuedMessage>,
+    ) -> Result<()> {
+        storage.replace_queue(id, undelivered)
+    }
+
+    pub fn push(
+        &self,
+        storage: &mut dyn Storage,
+        to: &str,
+        message: QueuedPayload,
+    ) -> std::result::Result<(), QueueError> {
+        match storage.account(to) {
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(QueueError::UnknownRecipient),
+            Err(err) => return Err(QueueError::Storage(err)),
+        }
+        let queue = storage.queued_messages(to).map_err(QueueError::Storage)?;
+        let queue_length = queue.len();
+        let queue = self.unexpired(queue);
+        if queue.len() >= self.limit {
+            return Err(QueueError::QueueFull);
+        }
+        if queue.len() < queue_length {
+            storage
+                .replace_queue(to, queue)
+                .map_err(QueueError::Storage)?;
+        }
+        storage
+            .queue_message(to, QueuedMessage::new(message))
+            .map_err(QueueError::Storage)
+    }
+
+    fn unexpired(&self, queue: Vec<QueuedMessage>) -> Vec<QueuedMessage> {
+        let ttl = self.ttl.as_secs();
+        queue
+            .into_iter()
+            .filter(|queued| queued.age() < ttl)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use encrypter_core::group::{new_group_id, GroupMessage, SenderKey};
+    use encrypter_core::identity::SignedPublicKey;
+    use encrypter_core::padding::PaddingPolicy;
+    use rand::rngs::OsRng;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn storage_with_account(id: &str) -> MemoryStorage {
+        let identity = ed25519_dalek::Keypair::generate(&mut OsRng);
+        let public_key = PublicKey::from(&StaticSecret::new(&mut OsRng));
+        let mut storage = MemoryStorage::new();
+        storage
+            .save_account(id, &SignedPublicKey::sign(id, &public_key, &identity))
+            .unwrap();
+        storage
+    }
+
+    fn group_message() -> GroupMessage {
+        SenderKey::new()
+            .encrypt(
+                new_group_id(),
+                "alice",
+                b"hello".to_vec(),
+                &PaddingPolicy::default(),
+            )
+            .unwrap()
+    }
+
+    fn messages(queue: Vec<QueuedMessage>) -> Vec<GroupMessage> {
+        queue
+            .into_iter()
+            .map(|queued| match queued.message {
+                QueuedPayload::GroupMessage(message) => message,
+                QueuedPayload::Message(_) => panic!("Expected a group message"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delivers_in_order_until_delivered() {
+        let queue = OfflineQueue::new(10, TTL);
+        let mut storage = storage_with_account("bob");
+        let sent: Vec<_> = (0..3).map(|_| group_message()).collect();
+        for message in &sent {
+            queue
+                .push(
+                    &mut storage,
+                    "bob",
+                    QueuedPayload::GroupMessage(message.clone()),
+                )
+                .unwrap();
+        }
+        let mut pending = queue.pending(&storage, "bob").unwrap();
+        assert_eq!(messages(pending.clone()), sent);
+        // Nothing is removed before it has been delivered
+        assert_eq!(messages(queue.pending(&storage, "bob").unwrap()), sent);
+
+        let undelivered = pending.split_off(1);
+        queue
+            .finish_delivery(&mut storage, "bob", undelivered)
+            .unwrap();
+        assert_eq!(
+            messages(queue.pending(&storage, "bob").unwrap()),
+            &sent[1..]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_recipient() {
+        let queue = OfflineQueue::new(10, TTL);
+        let mut storage = storage_with_account("bob");
+        let result = queue.push(
+            &mut storage,
+            "carol",
+            QueuedPayload::GroupMessage(group_message()),
+        );
+        assert!(matches!(result, Err(QueueError::UnknownRecipient)));
+        assert!(storage.queued_messages("carol").unwrap().is_empty());
+    }
+
+    #[test]
+    fn limits_messages_per_recipient() {
+        let queue = OfflineQueue::new(2, TTL);
+        let mut storage = storage_with_account("bob");
+        for _ in 0..2 {
+            queue
+                .push(
+                    &mut storage,
+                    "bob",
+                    QueuedPayload::GroupMessage(group_message()),
+                )
+                .unwrap();
+        }
+        let result = queue.push(
+            &mut storage,
+            "bob",
+            QueuedPayload::GroupMessage(group_message()),
+        );
+        assert!(matches!(result, Err(QueueError::QueueFull)));
+        assert_eq!(queue.pending(&storage, "bob").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn drops_expired_messages() {
+        let queue = OfflineQueue::new(2, TTL);
+        let mut storage = storage_with_account("bob");
+        for _ in 0..2 {
+            let mut expired = QueuedMessage::new(QueuedPayload::GroupMessage(group_message()));
+            expired.queued_at -= TTL.as_secs();
+            storage.queue_message("bob", expired).unwrap();
+        }
+        assert!(queue.pending(&storage, "bob").unwrap().is_empty());
+
+        // Expired messages don't count towards the limit and are removed when pushing
+        let message = group_message();
+        queue
+            .push(
+                &mut storage,
+                "bob",
+                QueuedPayload::GroupMessage(message.clone()),
+            )
+            .unwrap();
+        assert_eq!(storage.queued_messages("bob").unwrap().len(), 1);
+        assert_eq!(
+            messages(queue.pending(&storage, "bob").unwrap()),
+            vec![message]
+        );
+    }
+}
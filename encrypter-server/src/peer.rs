@@ -1,5 +1,6 @@
 use async_std::net::SocketAddr;
 use async_std::net::TcpStream;
+use encrypter_core::identity::SignedPublicKey;
 use encrypter_core::Result;
 use std::collections::hash_map::{Iter, Values};
 use std::collections::HashMap;
@@ -8,11 +9,11 @@ use std::collections::HashMap;
 pub struct Peer {
     pub peer_id: String,
     pub tcp_stream: TcpStream,
-    pub public_key: [u8; 32],
+    pub public_key: SignedPublicKey,
 }
 
 impl Peer {
-    pub fn new(peer_id: String, tcp_stream: TcpStream, public_key: [u8; 32]) -> Self {
+    pub fn new(peer_id: String, tcp_stream: TcpStream, public_key: SignedPublicKey) -> Self {
         Peer {
             peer_id,
             tcp_stream,
@@ -38,16 +39,28 @@ impl PeerSet {
         }
     }
 
+    // Both indexes must always point at the same peers so a peer is never
+    // allowed to replace an existing id or connection
     pub fn insert(&mut self, peer: Peer) -> Result<()> {
         if let Ok(ip) = peer.get_addr() {
+            if self.id_storage.contains_key(&peer.peer_id) {
+                return Err(format!("Peer with id {} already exists", peer.peer_id).into());
+            }
+            if let Some(existing_id) = self.ip_storage.get(&ip) {
+                return Err(format!("Ip {} is already mapped to peer {}", ip, existing_id).into());
+            }
             self.ip_storage.insert(ip, peer.peer_id.clone());
             self.id_storage.insert(peer.peer_id.clone(), peer);
             Ok(())
         } else {
-            Err("Could not insert peer: {:?}".into())
+            Err(format!("Could not insert peer: {:?}", peer).into())
         }
     }
 
+    pub fn contains_id(&self, id: &str) -> bool {
+        self.id_storage.contains_key(id)
+    }
+
     pub fn remove_by_ip(&mut self, ip: &SocketAddr) -> Option<Peer> {
         if let Some(id) = self.ip_storage.remove(ip) {
             if let Some(peer) = self.id_storage.remove(&id) {
@@ -95,11 +108,15 @@ impl PeerSet {
         self.id_storage.get(id)
     }
 
-    pub fn values(&self) -> Values<String, Peer> {
+    pub fn find_by_id_mut(&mut self, id: &str) -> Option<&mut Peer> {
+        self.id_storage.get_mut(id)
+    }
+
+    pub fn values(&self) -> Values<'_, String, Peer> {
         self.id_storage.values()
     }
     // TODO: maybe implement iterator trait to this datastructure
-    pub fn iter(&self) -> Iter<String, Peer> {
+    pub fn iter(&self) -> Iter<'_, String, Peer> {
         self.id_storage.iter()
     }
 }
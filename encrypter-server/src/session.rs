@@ -0,0 +1,102 @@
+use encrypter_core::identity::{self, SignedPublicKey, CHALLENGE_SIZE};
+use encrypter_core::Result;
+
+/// Registration state of a single connection. Every connection starts out unregistered
+/// after the handshake. A `NewConnection` moves it to challenged and it's only allowed
+/// to act as a peer once it has signed the challenge with the account's identity key,
+/// from then on it's bound to that id.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionState {
+    Unregistered,
+    Challenged(PendingRegistration),
+    Registered(String),
+}
+
+/// A registration waiting for the client to answer the challenge
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingRegistration {
+    pub id: String,
+    pub public_key: SignedPublicKey,
+    pub challenge: [u8; CHALLENGE_SIZE],
+}
+
+#[derive(Debug)]
+pub struct Session {
+    state: SessionState,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            state: SessionState::Unregistered,
+        }
+    }
+
+    pub fn state(&self) -> &SessionState {
+        &self.state
+    }
+
+    pub fn registered_id(&self) -> Option<&str> {
+        match &self.state {
+            SessionState::Registered(id) => Some(id),
+            SessionState::Unregistered | SessionState::Challenged(_) => None,
+        }
+    }
+
+    /// Starts a registration and returns the challenge the client has to sign.
+    /// A new registration replaces any pending one.
+    pub fn challenge(
+        &mut self,
+        id: String,
+        public_key: SignedPublicKey,
+    ) -> Result<[u8; CHALLENGE_SIZE]> {
+        if let SessionState::Registered(current_id) = &self.state {
+            return Err(format!(
+                "Connection is already registered as {}, can't register as {}",
+                current_id, id
+            )
+            .into());
+        }
+        let challenge = identity::new_challenge();
+        self.state = SessionState::Challenged(PendingRegistration {
+            id,
+            public_key,
+            challenge,
+        });
+        Ok(challenge)
+    }
+
+    /// Takes the pending registration, a challenge can only be answered once
+    pub fn take_pending(&mut self) -> Option<PendingRegistration> {
+        match std::mem::replace(&mut self.state, SessionState::Unregistered) {
+            SessionState::Challenged(pending) => Some(pending),
+            other => {
+                self.state = other;
+                None
+            }
+        }
+    }
+
+    pub fn register(&mut self, id: String) -> Result<()> {
+        match &self.state {
+            SessionState::Unregistered => {
+                self.state = SessionState::Registered(id);
+                Ok(())
+            }
+            SessionState::Challenged(pending) => Err(format!(
+                "Connection has a pending registration as {}, can't register as {}",
+                pending.id, id
+            )
+            .into()),
+            SessionState::Registered(current_id) => Err(format!(
+                "Connection is already registered as {}, can't register as {}",
+                current_id, id
+            )
+            .into()),
+        }
+    }
+
+    pub fn unregister(&mut self) {
+        self.state = SessionState::Unregistered;
+    }
+}
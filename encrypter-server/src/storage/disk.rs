@@ -0,0 +1,99 @@
+use super::{Prekeys, QueuedMessage, Storage};
+use encrypter_core::identity::SignedPublicKey;
+use encrypter_core::Result;
+use std::path::Path;
+
+const ACCOUNTS_TREE: &str = "accounts";
+const PREKEYS_TREE: &str = "prekeys";
+const QUEUES_TREE: &str = "queues";
+
+/// Stores accounts and queued messages in an embedded sled database.
+/// Queued messages are keyed by the recipient id followed by a separator and
+/// a monotonically increasing id, so a prefix scan returns them in order.
+pub struct DiskStorage {
+    db: sled::Db,
+    accounts: sled::Tree,
+    prekeys: sled::Tree,
+    queues: sled::Tree,
+}
+
+impl DiskStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let db = sled::open(path)?;
+        let accounts = db.open_tree(ACCOUNTS_TREE)?;
+        let prekeys = db.open_tree(PREKEYS_TREE)?;
+        let queues = db.open_tree(QUEUES_TREE)?;
+        Ok(DiskStorage {
+            db,
+            accounts,
+            prekeys,
+            queues,
+        })
+    }
+}
+
+// Ids can't contain control characters so 0 can't be part of an id
+fn queue_prefix(id: &str) -> Vec<u8> {
+    let mut prefix = id.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+impl Storage for DiskStorage {
+    fn account(&self, id: &str) -> Result<Option<SignedPublicKey>> {
+        match self.accounts.get(id)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_account(&mut self, id: &str, public_key: &SignedPublicKey) -> Result<()> {
+        self.accounts.insert(id, bincode::serialize(public_key)?)?;
+        self.accounts.flush()?;
+        Ok(())
+    }
+
+    fn prekeys(&self, id: &str) -> Result<Option<Prekeys>> {
+        match self.prekeys.get(id)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_prekeys(&mut self, id: &str, prekeys: &Prekeys) -> Result<()> {
+        self.prekeys.insert(id, bincode::serialize(prekeys)?)?;
+        self.prekeys.flush()?;
+        Ok(())
+    }
+
+    fn queue_message(&mut self, id: &str, message: QueuedMessage) -> Result<()> {
+        let mut key = queue_prefix(id);
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+        self.queues.insert(key, bincode::serialize(&message)?)?;
+        self.queues.flush()?;
+        Ok(())
+    }
+
+    fn queued_messages(&self, id: &str) -> Result<Vec<QueuedMessage>> {
+        self.queues
+            .scan_prefix(queue_prefix(id))
+            .values()
+            .map(|value| Ok(bincode::deserialize(&value?)?))
+            .collect()
+    }
+
+    fn replace_queue(&mut self, id: &str, messages: Vec<QueuedMessage>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for key in self.queues.scan_prefix(queue_prefix(id)).keys() {
+            batch.remove(key?);
+        }
+        for message in messages {
+            let mut key = queue_prefix(id);
+            key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+            batch.insert(key, bincode::serialize(&message)?);
+        }
+        self.queues.apply_batch(batch)?;
+        self.queues.flush()?;
+        Ok(())
+    }
+}
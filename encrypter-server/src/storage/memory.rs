@@ -0,0 +1,57 @@
+use super::{Prekeys, QueuedMessage, Storage};
+use encrypter_core::identity::SignedPublicKey;
+use encrypter_core::Result;
+use std::collections::HashMap;
+
+/// Keeps everything in memory, nothing survives a restart. Useful for tests
+/// and short lived servers.
+#[derive(Default)]
+pub struct MemoryStorage {
+    accounts: HashMap<String, SignedPublicKey>,
+    prekeys: HashMap<String, Prekeys>,
+    queues: HashMap<String, Vec<QueuedMessage>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn account(&self, id: &str) -> Result<Option<SignedPublicKey>> {
+        Ok(self.accounts.get(id).cloned())
+    }
+
+    fn save_account(&mut self, id: &str, public_key: &SignedPublicKey) -> Result<()> {
+        self.accounts.insert(id.to_string(), public_key.clone());
+        Ok(())
+    }
+
+    fn prekeys(&self, id: &str) -> Result<Option<Prekeys>> {
+        Ok(self.prekeys.get(id).cloned())
+    }
+
+    fn save_prekeys(&mut self, id: &str, prekeys: &Prekeys) -> Result<()> {
+        self.prekeys.insert(id.to_string(), prekeys.clone());
+        Ok(())
+    }
+
+    fn queue_message(&mut self, id: &str, message: QueuedMessage) -> Result<()> {
+        self.queues.entry(id.to_string()).or_default().push(message);
+        Ok(())
+    }
+
+    fn queued_messages(&self, id: &str) -> Result<Vec<QueuedMessage>> {
+        Ok(self.queues.get(id).cloned().unwrap_or_default())
+    }
+
+    fn replace_queue(&mut self, id: &str, messages: Vec<QueuedMessage>) -> Result<()> {
+        if messages.is_empty() {
+            self.queues.remove(id);
+        } else {
+            self.queues.insert(id.to_string(), messages);
+        }
+        Ok(())
+    }
+}
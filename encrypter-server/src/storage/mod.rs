@@ -0,0 +1,253 @@
+use encrypter_core::group::GroupMessage;
+use encrypter_core::identity::SignedPublicKey;
+use encrypter_core::sealed::SealedEnvelope;
+use encrypter_core::x3dh::{OneTimePrekey, SignedPrekey};
+use encrypter_core::{Result, ServerMessage};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod disk;
+mod memory;
+
+pub use disk::DiskStorage;
+pub use memory::MemoryStorage;
+
+/// Anything that can be waiting for an offline peer
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum QueuedPayload {
+    Message(SealedEnvelope),
+    GroupMessage(GroupMessage),
+}
+
+impl From<QueuedPayload> for ServerMessage {
+    fn from(payload: QueuedPayload) -> Self {
+        match payload {
+            QueuedPayload::Message(message) => ServerMessage::Message(message),
+            QueuedPayload::GroupMessage(message) => ServerMessage::GroupMessage(message),
+        }
+    }
+}
+
+/// A message waiting for an offline peer together with when it was queued
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct QueuedMessage {
+    /// Seconds since the unix epoch, wall clock time since it has to survive restarts
+    pub queued_at: u64,
+    pub message: QueuedPayload,
+}
+
+impl QueuedMessage {
+    pub fn new(message: QueuedPayload) -> Self {
+        QueuedMessage {
+            queued_at: unix_time(),
+            message,
+        }
+    }
+
+    pub fn age(&self) -> u64 {
+        unix_time().saturating_sub(self.queued_at)
+    }
+}
+
+/// The prekeys a peer has uploaded, one-time prekeys are removed as they are handed out
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Prekeys {
+    pub signed_prekey: SignedPrekey,
+    pub one_time_prekeys: Vec<OneTimePrekey>,
+}
+
+/// Server state that should outlive a single connection: the registered
+/// accounts with their keys and prekeys and the messages queued for offline peers.
+/// Messages for a peer are returned in the order they were queued.
+pub trait Storage: Send {
+    fn account(&self, id: &str) -> Result<Option<SignedPublicKey>>;
+    fn save_account(&mut self, id: &str, public_key: &SignedPublicKey) -> Result<()>;
+    fn prekeys(&self, id: &str) -> Result<Option<Prekeys>>;
+    fn save_prekeys(&mut self, id: &str, prekeys: &Prekeys) -> Result<()>;
+    fn queue_message(&mut self, id: &str, message: QueuedMessage) -> Result<()>;
+    fn queued_messages(&self, id: &str) -> Result<Vec<QueuedMessage>>;
+    /// Replaces all queued messages for the peer, used to drop expired or delivered messages
+    fn replace_queue(&mut self, id: &str, messages: Vec<QueuedMessage>) -> Result<()>;
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encrypter_core::padding::PaddingPolicy;
+    use encrypter_core::ratchet::Session;
+    use encrypter_core::{Message, MessageBody, MessageKind};
+    use rand::rngs::OsRng;
+    use std::path::PathBuf;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    // Removes the database directory when the test ends, even if it fails
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "encrypter-storage-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // sled releases the lock on the database from a background thread after the last
+    // handle is dropped, so opening it again right away can fail for a moment
+    fn reopen(dir: &TempDir) -> DiskStorage {
+        for _ in 0..50 {
+            if let Ok(storage) = DiskStorage::open(&dir.0) {
+                return storage;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        DiskStorage::open(&dir.0).unwrap()
+    }
+
+    fn account() -> SignedPublicKey {
+        let identity = ed25519_dalek::Keypair::generate(&mut OsRng);
+        let secret = StaticSecret::new(&mut OsRng);
+        SignedPublicKey::sign("bob", &PublicKey::from(&secret), &identity)
+    }
+
+    fn envelope() -> SealedEnvelope {
+        let alice = StaticSecret::new(&mut OsRng);
+        let bob = PublicKey::from(&StaticSecret::new(&mut OsRng));
+        let body = MessageBody::new(&MessageKind::Text("hello".to_string())).unwrap();
+        let message = Message::new("alice".to_string(), "bob".to_string(), body);
+        let encrypted = Session::new(&alice, bob)
+            .encrypt(message, &PaddingPolicy::default())
+            .unwrap();
+        SealedEnvelope::seal(&encrypted, &bob, &PaddingPolicy::default()).unwrap()
+    }
+
+    fn queued(storage: &impl Storage, id: &str) -> Vec<SealedEnvelope> {
+        storage
+            .queued_messages(id)
+            .unwrap()
+            .into_iter()
+            .map(|queued| match queued.message {
+                QueuedPayload::Message(envelope) => envelope,
+                QueuedPayload::GroupMessage(_) => panic!("Expected a pairwise message"),
+            })
+            .collect()
+    }
+
+    fn check_queue_order(storage: &mut impl Storage) {
+        let envelopes: Vec<_> = (0..3).map(|_| envelope()).collect();
+        for envelope in &envelopes {
+            let message = QueuedMessage::new(QueuedPayload::Message(envelope.clone()));
+            storage.queue_message("bob", message).unwrap();
+        }
+        storage
+            .queue_message(
+                "carol",
+                QueuedMessage::new(QueuedPayload::Message(envelope())),
+            )
+            .unwrap();
+        assert_eq!(queued(storage, "bob"), envelopes);
+        assert_eq!(queued(storage, "carol").len(), 1);
+        assert!(queued(storage, "dave").is_empty());
+    }
+
+    fn check_replace_queue(storage: &mut impl Storage) {
+        let envelopes: Vec<_> = (0..3).map(|_| envelope()).collect();
+        for envelope in &envelopes {
+            let message = QueuedMessage::new(QueuedPayload::Message(envelope.clone()));
+            storage.queue_message("bob", message).unwrap();
+        }
+        storage
+            .queue_message(
+                "carol",
+                QueuedMessage::new(QueuedPayload::Message(envelope())),
+            )
+            .unwrap();
+
+        let kept: Vec<_> = storage.queued_messages("bob").unwrap()[1..].to_vec();
+        storage.replace_queue("bob", kept).unwrap();
+        assert_eq!(queued(storage, "bob"), &envelopes[1..]);
+        // New messages go after the ones that were kept
+        let last = envelope();
+        storage
+            .queue_message(
+                "bob",
+                QueuedMessage::new(QueuedPayload::Message(last.clone())),
+            )
+            .unwrap();
+        assert_eq!(queued(storage, "bob").last(), Some(&last));
+
+        storage.replace_queue("bob", Vec::new()).unwrap();
+        assert!(queued(storage, "bob").is_empty());
+        assert_eq!(queued(storage, "carol").len(), 1);
+    }
+
+    fn check_accounts(storage: &mut impl Storage) -> SignedPublicKey {
+        let account = account();
+        assert_eq!(storage.account("bob").unwrap(), None);
+        storage.save_account("bob", &account).unwrap();
+        assert_eq!(storage.account("bob").unwrap(), Some(account.clone()));
+        account
+    }
+
+    #[test]
+    fn memory_storage_keeps_queue_order() {
+        check_queue_order(&mut MemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_storage_replaces_queue() {
+        check_replace_queue(&mut MemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_storage_saves_accounts() {
+        check_accounts(&mut MemoryStorage::new());
+    }
+
+    #[test]
+    fn disk_storage_keeps_queue_order() {
+        let dir = TempDir::new("queue-order");
+        check_queue_order(&mut DiskStorage::open(&dir.0).unwrap());
+    }
+
+    #[test]
+    fn disk_storage_replaces_queue() {
+        let dir = TempDir::new("replace-queue");
+        check_replace_queue(&mut DiskStorage::open(&dir.0).unwrap());
+    }
+
+    #[test]
+    fn disk_storage_keeps_accounts_and_queues_after_reopen() {
+        let dir = TempDir::new("reopen");
+        let envelope = envelope();
+        let account = {
+            let mut storage = DiskStorage::open(&dir.0).unwrap();
+            storage
+                .queue_message(
+                    "bob",
+                    QueuedMessage::new(QueuedPayload::Message(envelope.clone())),
+                )
+                .unwrap();
+            check_accounts(&mut storage)
+        };
+        let storage = reopen(&dir);
+        assert_eq!(storage.account("bob").unwrap(), Some(account));
+        assert_eq!(queued(&storage, "bob"), vec![envelope]);
+    }
+}